@@ -0,0 +1,125 @@
+use std::collections::HashMap;
+
+use super::httpdate::parse_http_date;
+use super::metadata::Metadata;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Precondition {
+
+    Proceed,
+    NotModified,
+    Failed,
+}
+
+pub fn evaluate_preconditions(method: &str, request: &HashMap<String, String>, metadata: &Metadata) -> Precondition {
+
+    if !(200..300).contains(&metadata.get_status()) { return Precondition::Proceed }
+
+    let etag = metadata.get_header("etag");
+    let last_modified = metadata.get_header("last-modified").and_then(|lm| parse_http_date(lm));
+    let is_read = method == "GET" || method == "HEAD";
+
+    if let Some(if_match) = request.get("if-match") {
+
+        if !etag_matches(if_match, etag, true) { return Precondition::Failed }
+    } else if let Some(since) = request.get("if-unmodified-since").and_then(|date| parse_http_date(date)) {
+
+        if let Some(last_modified) = last_modified {
+
+            if last_modified > since { return Precondition::Failed }
+        }
+    }
+
+    if let Some(if_none_match) = request.get("if-none-match") {
+
+        if etag_matches(if_none_match, etag, false) {
+
+            return if is_read { Precondition::NotModified } else { Precondition::Failed }
+        }
+    } else if is_read {
+
+        let since = request.get("if-modified-since").and_then(|date| parse_http_date(date));
+
+        if let (Some(since), Some(last_modified)) = (since, last_modified) {
+
+            if last_modified <= since { return Precondition::NotModified }
+        }
+    }
+
+    Precondition::Proceed
+}
+
+fn etag_matches(condition: &str, etag: Option<&String>, strong: bool) -> bool {
+
+    if condition.trim() == "*" { return true }
+
+    let etag = match etag {
+
+        Some(x) => { x.trim() },
+        None => { return false },
+    };
+
+    if strong && etag.starts_with("W/") { return false }
+
+    condition
+        .split(',')
+        .map(|candidate| candidate.trim())
+        .filter(|candidate| !(strong && candidate.starts_with("W/")))
+        .any(|candidate| opaque_tag(candidate) == opaque_tag(etag))
+}
+
+fn opaque_tag(etag: &str) -> &str {
+
+    etag.strip_prefix("W/").unwrap_or(etag)
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::cache::testing::{headers, metadata};
+
+    const VALIDATORS: [(&str, &str); 2] = [("etag", "W/\"v1\""), ("last-modified", "Sun, 06 Nov 1994 08:49:37 GMT")];
+
+    #[test]
+    fn if_none_match_uses_weak_comparison() {
+
+        let evaluate = |method: &str, condition: &str| evaluate_preconditions(method, &headers(&[("if-none-match", condition)]), &metadata(200, 0, &VALIDATORS));
+
+        assert_eq!(evaluate("GET", "\"v1\""), Precondition::NotModified);
+        assert_eq!(evaluate("HEAD", "\"v0\", W/\"v1\""), Precondition::NotModified);
+        assert_eq!(evaluate("GET", "*"), Precondition::NotModified);
+        assert_eq!(evaluate("GET", "\"v2\""), Precondition::Proceed);
+        assert_eq!(evaluate("POST", "\"v1\""), Precondition::Failed);
+    }
+
+    #[test]
+    fn if_match_uses_strong_comparison() {
+
+        let evaluate = |condition: &str| evaluate_preconditions("GET", &headers(&[("if-match", condition)]), &metadata(200, 0, &VALIDATORS));
+
+        assert_eq!(evaluate("W/\"v1\""), Precondition::Failed);
+        assert_eq!(evaluate("*"), Precondition::Proceed);
+    }
+
+    #[test]
+    fn dates_are_compared_when_there_is_no_etag_condition() {
+
+        let evaluate = |name: &str, date: &str| evaluate_preconditions("GET", &headers(&[(name, date)]), &metadata(200, 0, &VALIDATORS));
+
+        assert_eq!(evaluate("if-modified-since", "Sun, 06 Nov 1994 08:49:37 GMT"), Precondition::NotModified);
+        assert_eq!(evaluate("if-modified-since", "Sat, 05 Nov 1994 08:49:37 GMT"), Precondition::Proceed);
+        assert_eq!(evaluate("if-modified-since", "not a date"), Precondition::Proceed);
+        assert_eq!(evaluate("if-unmodified-since", "Sat, 05 Nov 1994 08:49:37 GMT"), Precondition::Failed);
+        assert_eq!(evaluate("if-unmodified-since", "Sun, 06 Nov 1994 08:49:37 GMT"), Precondition::Proceed);
+
+        let both = headers(&[("if-none-match", "\"v2\""), ("if-modified-since", "Sun, 06 Nov 1994 08:49:37 GMT")]);
+        assert_eq!(evaluate_preconditions("GET", &both, &metadata(200, 0, &VALIDATORS)), Precondition::Proceed);
+    }
+
+    #[test]
+    fn only_successful_responses_are_evaluated() {
+
+        assert_eq!(evaluate_preconditions("GET", &headers(&[("if-none-match", "*")]), &metadata(404, 0, &VALIDATORS)), Precondition::Proceed);
+    }
+}
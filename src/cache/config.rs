@@ -0,0 +1,34 @@
+use super::store::CacheBackend;
+
+pub const CACHE_SCHEME: &str = "http";
+pub const IGNORED_QUERY_PARAMS: [&str; 7] = ["utm_source", "utm_medium", "utm_campaign", "utm_term", "utm_content", "fbclid", "gclid"];
+pub const HEURISTIC_PERCENT: u32 = 10;
+pub const HEURISTIC_MAX_TTL: u64 = 86400;
+pub const CACHEABLE_METHODS: [&str; 2] = ["GET", "HEAD"];
+pub const CACHEABLE_STATUS: [u16; 11] = [200, 203, 204, 300, 301, 308, 404, 405, 410, 414, 501];
+pub const NEGATIVE_CACHE_ENABLED: bool = true;
+pub const NEGATIVE_CACHE_TTL: [(u16, u64); 3] = [(404, 30), (502, 5), (503, 5)];
+pub const CACHE_SET_COOKIE: bool = false;
+pub const CACHE_AUTHORIZED: bool = false;
+pub const STALE_WHILE_REVALIDATE_DEFAULT: u64 = 0;
+pub const STALE_WHILE_REVALIDATE_MAX: u64 = 60;
+pub const STALE_IF_ERROR_DEFAULT: u64 = 0;
+pub const STALE_IF_ERROR_MAX: u64 = 600;
+pub const REVALIDATION_THREADS: usize = 2;
+pub const REVALIDATION_QUEUE_SIZE: usize = 64;
+pub const COALESCE_TIMEOUT: u64 = 10;
+pub const PURGE_LOG_SIZE: usize = 1024;
+pub const CACHE_BACKEND: CacheBackend = CacheBackend::Tiered;
+pub const MEMORY_CACHE_SIZE: u64 = 64 * 1024 * 1024;
+pub const MEMORY_MAX_OBJECT_SIZE: u64 = 1024 * 1024;
+pub const DISK_CACHE_SIZE: u64 = 1024 * 1024 * 1024;
+pub const DISK_CACHE_ENTRIES: usize = 100_000;
+pub const CACHE_MAX_OBJECT_SIZE: u64 = 8 * 1024 * 1024;
+pub const CLEANER_GRACE_PERIOD: u64 = STALE_IF_ERROR_MAX;
+pub const CLEANER_FULL_SCAN_INTERVAL: u64 = 3600;
+pub const MAX_RANGES: usize = 16;
+pub const ZERO_COPY_MIN_SIZE: u64 = 64 * 1024;
+pub const CACHE_TEMP_DIR: &str = "tmp";
+pub const WRITER_QUEUE_SIZE: usize = 256;
+pub const WRITER_THREADS: usize = 2;
+pub const WRITER_BLOCK_WHEN_FULL: bool = false;
@@ -0,0 +1,42 @@
+const POLYNOMIAL: u32 = 0xEDB88320;
+const TABLE: [u32; 256] = build_table();
+
+const fn build_table() -> [u32; 256] {
+
+    let mut table = [0u32; 256];
+    let mut index = 0;
+
+    while index < 256 {
+
+        let mut crc = index as u32;
+        let mut bit = 0;
+
+        while bit < 8 {
+
+            crc = if crc & 1 == 1 { (crc >> 1) ^ POLYNOMIAL } else { crc >> 1 };
+            bit += 1;
+        }
+
+        table[index] = crc;
+        index += 1;
+    }
+
+    table
+}
+
+pub fn checksum(data: &[u8]) -> u32 {
+
+    update(0, data)
+}
+
+pub fn update(crc: u32, data: &[u8]) -> u32 {
+
+    let mut crc = !crc;
+
+    for byte in data {
+
+        crc = TABLE[((crc ^ *byte as u32) & 0xFF) as usize] ^ (crc >> 8);
+    }
+
+    !crc
+}
@@ -0,0 +1,331 @@
+use log::info;
+use uuid::Uuid;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, MutexGuard};
+use std::time::Duration;
+
+use super::config::CACHE_TEMP_DIR;
+use super::filedata::{clean_temp_dir, create_file_path, delete_file, FileData};
+use super::index::{lock_index, write_index, CacheIndex, IndexEntry};
+use super::metadata::Metadata;
+use super::store::{CacheStore, StoreEntry, StoreStats};
+
+// One file per entry, named after the hash of its variant key, tracked by the persistent index.
+pub struct DiskStore {
+
+    folder: PathBuf,
+    temp_dir: PathBuf,
+    index: Mutex<CacheIndex>,
+}
+
+impl DiskStore {
+
+    // A missing or corrupt index starts empty; the cleaner's startup scan fills it from the folder.
+    pub fn open(folder: &Path, max_bytes: u64, max_entries: usize) -> Self {
+
+        let index = match CacheIndex::load(folder, max_bytes, max_entries) {
+
+            Ok(x) => { x },
+            Err(e) => {
+
+                println!("Rebuilding cache index: {e}");
+                CacheIndex::new(max_bytes, max_entries)
+            },
+        };
+
+        let temp_dir = folder.join(CACHE_TEMP_DIR);
+        let orphans = clean_temp_dir(&temp_dir);
+        if orphans > 0 { println!("Removed {orphans} unfinished cache writes"); }
+
+        DiskStore { folder: folder.to_path_buf(), temp_dir, index: Mutex::new(index) }
+    }
+
+    pub fn contains(&self, key: &str) -> bool {
+
+        match self.path(key) {
+
+            Ok(path) => { self.lock().contains(&path) },
+            Err(_) => { false },
+        }
+    }
+
+    // Marks the entry as recently used; returns false if the index does not know it.
+    pub fn touch(&self, key: &str) -> bool {
+
+        let path = match self.path(key) {
+
+            Ok(x) => { x },
+            Err(_) => { return false },
+        };
+
+        let mut index = self.lock();
+        if !index.contains(&path) { return false }
+
+        index.touch(&path);
+        true
+    }
+
+    // Returns the keys evicted to make room, which are already gone from disk.
+    pub fn write_evicting(&self, filedata: &FileData) -> Option<Vec<String>> {
+
+        let path = self.path(&filedata.metadata.key).ok()?;
+        if !filedata.write_file(&path, &self.temp_dir) { return None }
+
+        let size = filedata.metadata.get_size() + filedata.metadata.content_length;
+        let evicted = self.lock().insert(&path, IndexEntry::from_metadata(&filedata.metadata, size));
+
+        let mut keys = Vec::new();
+
+        for (path, key) in evicted {
+
+            info!("Evicting cache file {path:?} to stay within the disk limits");
+            delete_file(path);
+            keys.push(key);
+        }
+
+        Some(keys)
+    }
+
+    fn path(&self, key: &str) -> Result<PathBuf, String> {
+
+        create_file_path(&self.folder, key)
+    }
+
+    fn lock(&self) -> MutexGuard<'_, CacheIndex> {
+
+        lock_index(&self.index)
+    }
+
+    fn scan_folder(&self, folder: &Path, cutoff: Duration, reclaimed: &mut StoreStats) {
+
+        let entries = match fs::read_dir(folder) {
+
+            Ok(x) => { x },
+            Err(_) => { return },
+        };
+
+        for dir_entry in entries.flatten() {
+
+            let path = dir_entry.path();
+
+            if path.is_dir() {
+
+                if path.file_name().is_some_and(|name| name == CACHE_TEMP_DIR) { continue }
+
+                self.scan_folder(&path, cutoff, reclaimed);
+                let _ = fs::remove_dir(&path);
+                continue
+            }
+
+            if is_legacy_temp_file(&path) && delete_file(path.clone()) { reclaimed.entries += 1; continue }
+            if path.extension().is_some() || self.lock().contains(&path) { continue }
+
+            let size = dir_entry.metadata().map(|m| m.len()).unwrap_or(0);
+
+            match Metadata::parse_file(&path) {
+
+                // A file is only reachable through the path of the key it holds.
+                Ok(metadata) if metadata.expires() > cutoff && self.path(&metadata.key).is_ok_and(|x| x == path) => {
+
+                    let evicted = self.lock().insert(&path, IndexEntry::from_metadata(&metadata, size));
+
+                    for (evicted_path, _) in evicted {
+
+                        if delete_file(evicted_path) { reclaimed.entries += 1; }
+                    }
+                },
+                _ => {
+
+                    if delete_file(path) {
+
+                        reclaimed.entries += 1;
+                        reclaimed.bytes += size;
+                    }
+                },
+            }
+        }
+    }
+}
+
+impl CacheStore for DiskStore {
+
+    fn metadata(&self, key: &str) -> Result<Metadata, String> {
+
+        let path = self.path(key)?;
+
+        match Metadata::parse_file(&path) {
+
+            Ok(x) => { Ok(x) },
+            Err(e) => {
+
+                if path.is_file() {
+
+                    info!("Discarding unreadable cache file {path:?}: {e}");
+                    self.delete(key);
+                }
+
+                Err(e)
+            },
+        }
+    }
+
+    fn read(&self, key: &str, metadata: Metadata) -> Result<FileData, String> {
+
+        let path = self.path(key)?;
+        let verified = self.lock().is_verified(&path);
+        let filedata = FileData::parse_file(&path, metadata, verified)?;
+
+        let mut index = self.lock();
+        index.set_verified(&path);
+        index.touch(&path);
+
+        Ok(filedata)
+    }
+
+    fn write(&self, filedata: &FileData) -> bool {
+
+        self.write_evicting(filedata).is_some()
+    }
+
+    fn refresh(&self, key: &str, metadata: &mut Metadata, ttl: Duration) -> Result<(), String> {
+
+        let path = self.path(key)?;
+
+        metadata.refresh_file(&path, ttl)?;
+        self.lock().set_expires(&path, metadata.expires());
+
+        Ok(())
+    }
+
+    fn delete(&self, key: &str) -> bool {
+
+        let path = match self.path(key) {
+
+            Ok(x) => { x },
+            Err(_) => { return false },
+        };
+
+        self.lock().remove(&path);
+        delete_file(path)
+    }
+
+    fn vary_fields(&self, key: &str) -> Vec<String> {
+
+        self.lock().vary_fields(key)
+    }
+
+    fn entries(&self) -> Vec<StoreEntry> {
+
+        self.lock()
+            .entries()
+            .into_iter()
+            .map(|(_, entry)| StoreEntry { key: entry.key, tags: entry.tags, size: entry.size, expires: entry.expires })
+            .collect()
+    }
+
+    fn stats(&self) -> StoreStats {
+
+        let index = self.lock();
+
+        StoreStats { entries: index.len(), bytes: index.bytes() }
+    }
+
+    fn remove_expired(&self, cutoff: Duration) -> StoreStats {
+
+        let expired = self.lock().expired(cutoff);
+        let mut reclaimed = StoreStats::default();
+
+        for (path, size) in expired {
+
+            self.lock().remove(&path);
+
+            if delete_file(path.clone()) {
+
+                reclaimed.entries += 1;
+                reclaimed.bytes += size;
+            }
+
+            remove_empty_parents(&self.folder, &path);
+        }
+
+        reclaimed
+    }
+
+    fn scan(&self, cutoff: Duration) -> StoreStats {
+
+        let mut reclaimed = StoreStats::default();
+        self.scan_folder(&self.folder, cutoff, &mut reclaimed);
+
+        reclaimed
+    }
+
+    fn flush(&self) {
+
+        let data = {
+
+            let mut index = self.lock();
+            if !index.is_dirty() { return }
+
+            index.snapshot(&self.folder)
+        };
+
+        if let Err(e) = write_index(&self.folder, &data) {
+
+            println!("Failed to save the cache index: {e}");
+            self.lock().mark_dirty();
+        }
+    }
+}
+
+fn is_legacy_temp_file(path: &Path) -> bool {
+
+    path.extension().and_then(|ext| ext.to_str()).is_some_and(|ext| Uuid::parse_str(ext).is_ok())
+}
+
+fn remove_empty_parents(folder: &Path, path: &Path) {
+
+    let mut parent = path.parent();
+
+    while let Some(dir) = parent {
+
+        if dir == folder || !dir.starts_with(folder) || fs::remove_dir(dir).is_err() { break }
+        parent = dir.parent();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::cache::testing::{filedata, temp_path};
+
+    #[test]
+    fn writes_reads_and_rescans_entries() {
+
+        let folder = temp_path();
+        let key = "GET http://localhost/a\naccept-language: en";
+
+        let store = DiskStore::open(&folder, 1 << 20, 10);
+        assert!(store.write(&filedata(key, b"hello", &[])));
+        assert!(store.contains(key));
+
+        let read = store.read(key, store.metadata(key).unwrap()).unwrap();
+        assert_eq!(read.get_content(), Some(&b"hello"[..]));
+        assert_eq!(store.entries()[0].key, key);
+
+        // A stray copy under the wrong name is dropped by the scan.
+        fs::copy(store.path(key).unwrap(), folder.join("stray")).unwrap();
+
+        let reopened = DiskStore::open(&folder, 1 << 20, 10);
+        assert_eq!(reopened.scan(Duration::ZERO).entries, 1);
+        assert!(reopened.contains(key));
+        assert!(!folder.join("stray").exists());
+
+        let expires = reopened.entries()[0].expires;
+        assert_eq!(reopened.remove_expired(expires).entries, 1);
+        assert!(reopened.metadata(key).is_err());
+
+        fs::remove_dir_all(&folder).unwrap();
+    }
+}
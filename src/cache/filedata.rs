@@ -1,47 +1,82 @@
+use super::config::ZERO_COPY_MIN_SIZE;
+use super::crc32;
+use super::key::key_path;
 use super::metadata::Metadata;
-use std::path::{PathBuf, Path};
+use std::path::{Component, PathBuf, Path};
 use std::io::{prelude::*, BufReader};
 use std::io::SeekFrom;
+use std::sync::Arc;
 use uuid::Uuid;
+use std::collections::HashMap;
 use std::fs::{self, File};
 
+#[derive(Debug)]
+pub enum CacheBody {
+
+    Memory(Arc<Vec<u8>>),
+    File { file: File, offset: u64 },
+}
+
 #[derive(Debug)]
 pub struct FileData {
 
-    pub path: PathBuf,
     pub metadata: Metadata,
-    pub content_data: Vec<u8>,
+    pub body: CacheBody,
 }
 
 impl FileData {
 
-    pub fn default(ttl: u64, content_length: u64, path: PathBuf, content_data: Vec<u8>, content_type: Option<String>) -> Result<Self, String> {
-
-        if !check_valid_path(&path) { return Err(format!("Path no valido al internar crear FileData. path: {path:?} ")) }
+    pub fn default(ttl: u64, content_length: u64, key: &str, content_data: Vec<u8>, status: u16, headers: &HashMap<String, String>) -> Result<Self, String> {
 
-        let metadata = match Metadata::default(ttl, content_length, content_type) {
+        let mut metadata = match Metadata::default(ttl, content_length, status, headers) {
 
             Ok(x) => { x },
             _ => { return Err("falló al crear el metadata. :(".to_string()) },
         };
 
+        metadata.checksum = crc32::checksum(&content_data);
+        metadata.key = key.to_string();
+
         Ok(FileData {
 
-            path,
             metadata,
-            content_data,
+            body: CacheBody::Memory(Arc::new(content_data)),
         })
     }
 
-    pub fn parse_file(path: PathBuf, metadata: Metadata) -> Result<FileData, String> {
+    pub fn parse_file(path: &Path, metadata: Metadata, verified: bool) -> Result<FileData, String> {
 
-        let file = match File::open(path.as_path()) {
+        let mut file = match File::open(path) {
 
             Ok(x) => { x },
             Err(e) => { return Err(e.to_string()) },
         };
+
+        let offset = metadata.get_size();
+
+        match file.metadata() {
+
+            Ok(x) if x.len() == offset + metadata.content_length => {},
+            Ok(_) => { return Err("Cache file length mismatch.".to_string()) },
+            Err(e) => { return Err(e.to_string()) },
+        }
+
+        if let Err(e) = file.seek(SeekFrom::Start(offset)) { return Err(e.to_string()) }
+
+        // Large bodies are streamed to the client straight from the file, so their checksum is
+        // only verified the first time they are read.
+        if metadata.content_length >= ZERO_COPY_MIN_SIZE {
+
+            if !verified {
+
+                verify_checksum(&mut file, &metadata)?;
+                if let Err(e) = file.seek(SeekFrom::Start(offset)) { return Err(e.to_string()) }
+            }
+
+            return Ok(FileData { metadata, body: CacheBody::File { file, offset } })
+        }
+
         let mut reader = BufReader::new(file);
-        reader.seek(SeekFrom::Start(metadata.get_size())).unwrap();
 
         let mut content_data = vec![0; metadata.content_length as usize];
 
@@ -49,11 +84,12 @@ impl FileData {
 
             Ok(_) => {
 
+                if crc32::checksum(&content_data) != metadata.checksum { return Err("Cache file checksum mismatch.".to_string()) }
+
                 Ok(FileData {
 
-                    path,
                     metadata,
-                    content_data,
+                    body: CacheBody::Memory(Arc::new(content_data)),
                 })
             },
 
@@ -61,118 +97,120 @@ impl FileData {
         }
     }
 
-    pub fn write_file(&self) -> bool {
+    pub fn write_file(&self, path: &Path, temp_dir: &Path) -> bool {
+
+        let content = match &self.body {
 
-        let uuid = Uuid::new_v4();
-        let mut written_path = self.path.clone();
-        written_path.set_extension(uuid.to_string());
+            CacheBody::Memory(x) => { x.as_slice() },
+            CacheBody::File { .. } => { return false; },
+        };
 
-        let parent = match self.get_path().parent() {
+        let parent = match path.parent() {
 
             Some(x) => { x },
             None => { return false; },
         };
 
-        match fs::create_dir_all(parent) {
+        match fs::create_dir_all(parent).and_then(|_| fs::create_dir_all(temp_dir)) {
 
             Ok(_) => {},
             Err(_) => { return false; },
         }
 
-        let mut file = match File::create(&written_path) {
-
-            Ok(file) => { file },
-            Err(_) => { return false; },
-        };
+        let temp_path = temp_dir.join(Uuid::new_v4().simple().to_string());
 
-        let content_type = self.format_content_type();
-        let header = FileData::generate_header(self, content_type);
+        let written = File::create(&temp_path)
+            .and_then(|mut file| {
 
-        let mut index = 0;
+                file.write_all(&self.generate_header())?;
+                file.write_all(content)?;
+                file.sync_data()
+            })
+            .and_then(|_| fs::rename(&temp_path, path));
 
-        while index < header.len() {
+        match written {
 
-            match file.write(&header[index..]) {
+            Ok(_) => { true },
+            Err(_) => {
 
-                Ok(bytes_written) => {
-                    index += bytes_written;
-                    file.flush().unwrap();
-                },
-                Err(_) => { return false; },
-            }
+                delete_file(temp_path);
+                false
+            },
         }
+    }
 
-        let content = self.content_data.as_slice();
+    pub fn generate_header(&self) -> Vec<u8> {
 
-        index = 0;
+        [self.metadata.format_preamble(), self.metadata.format_extensions()].concat()
+    }
 
-        while index < content.len() {
+    pub fn get_content(&self) -> Option<&[u8]> {
 
-            match file.write(&content[index..]) {
+        match &self.body {
 
-                Ok(bytes_written) => {
-                    index += bytes_written;
-                    file.flush().unwrap();
-                },
-                Err(_) => { return false; },
-            }
+            CacheBody::Memory(x) => { Some(x.as_slice()) },
+            CacheBody::File { .. } => { None },
         }
-
-        std::fs::rename(&written_path.as_path(), &self.path.as_path()).unwrap();
-
-        true
     }
 
-    pub fn generate_header(&self, content_type: Vec<u8>) -> Vec<u8> {
+    pub fn share(&self) -> Option<FileData> {
 
-        let ts = self.metadata.creation_date.as_secs().to_le_bytes().to_vec();
-        let ttl = self.metadata.ttl.as_secs().to_le_bytes().to_vec();
-        let length = self.metadata.content_length.to_le_bytes().to_vec();
+        match &self.body {
 
-        [content_type, ts, ttl, length].concat()
+            CacheBody::Memory(x) => { Some(FileData { metadata: self.metadata.clone(), body: CacheBody::Memory(Arc::clone(x)) }) },
+            CacheBody::File { .. } => { None },
+        }
     }
+}
 
-    pub fn get_path(&self) -> &PathBuf {
+fn verify_checksum(file: &mut File, metadata: &Metadata) -> Result<(), String> {
 
-        &self.path
-    }
+    let mut reader = BufReader::new(file.take(metadata.content_length));
+    let mut crc = 0;
 
-    pub fn get_content(&self) -> &Vec<u8> {
+    loop {
 
-        &self.content_data
-    }
+        let length = match reader.fill_buf() {
 
-    pub fn format_content_type(&self) -> Vec<u8> {
+            Ok([]) => { break },
+            Ok(x) => { crc = crc32::update(crc, x); x.len() },
+            Err(e) => { return Err(e.to_string()) },
+        };
 
-        match &self.metadata.get_content_type() {
+        reader.consume(length);
+    }
 
-            Some(ct) => {
+    if crc != metadata.checksum { return Err("Cache file checksum mismatch.".to_string()) }
 
-                let mut ct = ct.clone().trim().to_string();
-                ct.push('\n');
-                ct.as_bytes().to_vec()
-            },
-            None => {
+    Ok(())
+}
 
-                let mut ct = String::new();
-                ct.push('\n');
-                ct.as_bytes().to_vec()
-            },
-        }
-    }
+pub fn create_file_path(cache_folder: &Path, key: &str) -> Result<PathBuf, String> {
+
+    confine_path(cache_folder, &key_path(cache_folder, key))
 }
 
-pub fn check_valid_path(path: &Path) -> bool {
+pub fn confine_path(cache_folder: &Path, path: &Path) -> Result<PathBuf, String> {
 
-    !path.is_dir()
+    let relative = match path.strip_prefix(cache_folder) {
+
+        Ok(x) => { x },
+        Err(_) => { return Err(format!("Path outside of the cache folder. path: {path:?}")) },
+    };
+
+    if relative.components().all(|component| matches!(component, Component::Normal(_))) { Ok(path.to_path_buf()) }
+    else { Err(format!("Path escapes the cache folder. path: {path:?}")) }
 }
 
-pub fn create_file_path(cache_folder: &Path, file_route: String) -> PathBuf {
+pub fn clean_temp_dir(temp_dir: &Path) -> usize {
+
+    let entries = match fs::read_dir(temp_dir) {
 
-    let mut path = cache_folder.to_path_buf();
-    if file_route.starts_with('/') { path.push(file_route.strip_prefix('/').unwrap()); } else { path.push(file_route); }
+        Ok(x) => { x },
+        Err(_) => { return 0 },
+    };
 
-    path
+    entries.flatten().filter(|entry| entry.path().is_file() && delete_file(entry.path())).count()
 }
 
 pub fn delete_file(path: PathBuf) -> bool {
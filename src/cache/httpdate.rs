@@ -0,0 +1,84 @@
+use std::time::Duration;
+
+const MONTHS: [&str; 12] = ["Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec"];
+
+pub fn parse_http_date(value: &str) -> Option<Duration> {
+
+    let parts: Vec<&str> = value.split([' ', ',', '-']).filter(|part| !part.is_empty()).collect();
+
+    // IMF-fixdate "Sun, 06 Nov 1994 08:49:37 GMT" and RFC 850 "Sunday, 06-Nov-94 08:49:37 GMT"
+    // share the day-month-year order; asctime "Sun Nov  6 08:49:37 1994" puts the year last.
+    let (day, month, year, time) = match parts.as_slice() {
+
+        [_, day, month, year, time, "GMT"] => { (*day, *month, *year, *time) },
+        [_, month, day, time, year] => { (*day, *month, *year, *time) },
+        _ => { return None },
+    };
+
+    let day: u64 = day.parse().ok()?;
+    let month = MONTHS.iter().position(|m| *m == month)? as u64 + 1;
+    let year: u64 = match year.parse::<u64>().ok()? {
+
+        x if x < 70 => { x + 2000 },
+        x if x < 100 => { x + 1900 },
+        x => { x },
+    };
+
+    let mut time = time.split(':').map(|part| part.parse::<u64>());
+    let (hour, minute, second) = match (time.next(), time.next(), time.next()) {
+
+        (Some(Ok(h)), Some(Ok(m)), Some(Ok(s))) if h < 24 && m < 60 && s < 61 => { (h, m, s) },
+        _ => { return None },
+    };
+
+    if !(1970..=9999).contains(&year) || day == 0 || day > 31 { return None }
+
+    let days = days_from_civil(year, month, day);
+
+    Some(Duration::from_secs(days * 86400 + hour * 3600 + minute * 60 + second))
+}
+
+fn days_from_civil(year: u64, month: u64, day: u64) -> u64 {
+
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year / 400;
+    let yoe = year - era * 400;
+    let mp = (month + 9) % 12;
+    let doy = (153 * mp + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+
+    era * 146097 + doe - 719468
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn parses_all_three_formats() {
+
+        let expected = Some(Duration::from_secs(784111777));
+
+        assert_eq!(parse_http_date("Sun, 06 Nov 1994 08:49:37 GMT"), expected);
+        assert_eq!(parse_http_date("Sunday, 06-Nov-94 08:49:37 GMT"), expected);
+        assert_eq!(parse_http_date("Sun Nov  6 08:49:37 1994"), expected);
+    }
+
+    #[test]
+    fn rejects_out_of_range_dates() {
+
+        assert_eq!(parse_http_date("Thu, 01 Jan 1960 00:00:00 GMT"), None);
+        assert_eq!(parse_http_date("Fri, 01 Jan 10000 00:00:00 GMT"), None);
+        assert_eq!(parse_http_date("Fri, 01 Jan 18446744073709551615 00:00:00 GMT"), None);
+        assert_eq!(parse_http_date("Sun, 32 Nov 1994 08:49:37 GMT"), None);
+        assert_eq!(parse_http_date("Sun, 06 Nov 1994 24:00:00 GMT"), None);
+        assert_eq!(parse_http_date("not a date"), None);
+    }
+
+    #[test]
+    fn accepts_the_last_supported_year() {
+
+        assert_eq!(parse_http_date("Fri, 31 Dec 9999 23:59:59 GMT"), Some(Duration::from_secs(253402300799)));
+    }
+}
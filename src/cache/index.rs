@@ -0,0 +1,409 @@
+//! Persistent index of the disk cache, saved as `INDEX_FILE` in the cache folder
+//! (all integers little-endian):
+//!
+//! | size | field                                       |
+//! |------|---------------------------------------------|
+//! | 4    | magic `RPLI`                                |
+//! | 2    | format version                              |
+//! | 8    | entry count                                 |
+//! | ...  | entries, least recently used first          |
+//! | 4    | CRC32 (IEEE) of everything before it        |
+//!
+//! Each entry is `[u16 len][path relative to the folder][u32 len][key][u32 len][surrogate keys]
+//! [u32 len][vary fields][u64 size][u64 expiry]`, with surrogate keys separated by spaces and
+//! vary fields by commas. The key is the variant key, so it includes the vary field lines.
+//! A missing or corrupt index is rebuilt by scanning the cache folder.
+
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fs::{self, File};
+use std::io::prelude::*;
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, MutexGuard};
+use std::time::Duration;
+
+use super::crc32;
+use super::filedata::delete_file;
+use super::metadata::Metadata;
+use super::vary::{primary_key, vary_fields};
+
+pub const INDEX_FILE: &str = "index.db";
+const INDEX_MAGIC: [u8; 4] = *b"RPLI";
+const INDEX_VERSION: u16 = 4;
+
+#[derive(Clone)]
+pub struct IndexEntry {
+
+    pub key: String,
+    pub tags: Vec<String>,
+    pub vary: Vec<String>,
+    pub size: u64,
+    pub expires: Duration,
+    last_access: u64,
+    verified: bool,
+}
+
+impl IndexEntry {
+
+    pub fn new(key: &str, tags: Vec<String>, vary: Vec<String>, size: u64, expires: Duration) -> Self {
+
+        IndexEntry { key: key.to_string(), tags, vary, size, expires, last_access: 0, verified: false }
+    }
+
+    pub fn from_metadata(metadata: &Metadata, size: u64) -> Self {
+
+        let vary = metadata.get_header("vary").and_then(|vary| vary_fields(vary)).unwrap_or_default();
+
+        IndexEntry::new(&metadata.key, metadata.get_surrogate_keys(), vary, size, metadata.expires())
+    }
+}
+
+pub struct CacheIndex {
+
+    entries: HashMap<PathBuf, IndexEntry>,
+    recency: BTreeMap<u64, PathBuf>,
+    expiry: BTreeSet<(Duration, PathBuf)>,
+    variants: HashMap<String, Vec<String>>,
+    bytes: u64,
+    tick: u64,
+    max_bytes: u64,
+    max_entries: usize,
+    dirty: bool,
+}
+
+impl CacheIndex {
+
+    pub fn new(max_bytes: u64, max_entries: usize) -> Self {
+
+        CacheIndex {
+
+            entries: HashMap::new(),
+            recency: BTreeMap::new(),
+            expiry: BTreeSet::new(),
+            variants: HashMap::new(),
+            bytes: 0,
+            tick: 0,
+            max_bytes,
+            max_entries,
+            dirty: false,
+        }
+    }
+
+    pub fn load(folder: &Path, max_bytes: u64, max_entries: usize) -> Result<Self, String> {
+
+        let data = match fs::read(folder.join(INDEX_FILE)) {
+
+            Ok(x) => { x },
+            Err(e) => { return Err(e.to_string()) },
+        };
+
+        if data.len() < 18 || data[0..4] != INDEX_MAGIC { return Err("Not a cache index: bad magic number.".to_string()) }
+
+        let (body, crc) = data.split_at(data.len() - 4);
+        if crc32::checksum(body) != u32::from_le_bytes(crc.try_into().unwrap()) { return Err("Cache index checksum mismatch.".to_string()) }
+
+        let version = u16::from_le_bytes(body[4..6].try_into().unwrap());
+        if version != INDEX_VERSION { return Err(format!("Unsupported cache index version {version}.")) }
+
+        let count = u64::from_le_bytes(body[6..14].try_into().unwrap());
+        let mut reader = IndexReader { rest: &body[14..] };
+        let mut index = CacheIndex::new(max_bytes, max_entries);
+
+        for _ in 0..count {
+
+            let path_length = u16::from_le_bytes(reader.take(2)?.try_into().unwrap()) as usize;
+            let path = folder.join(String::from_utf8_lossy(reader.take(path_length)?).as_ref());
+            let key_length = u32::from_le_bytes(reader.take(4)?.try_into().unwrap()) as usize;
+            let key = String::from_utf8_lossy(reader.take(key_length)?).to_string();
+            let tags_length = u32::from_le_bytes(reader.take(4)?.try_into().unwrap()) as usize;
+            let tags: Vec<String> = String::from_utf8_lossy(reader.take(tags_length)?).split_whitespace().map(String::from).collect();
+            let vary_length = u32::from_le_bytes(reader.take(4)?.try_into().unwrap()) as usize;
+            let vary = vary_fields(&String::from_utf8_lossy(reader.take(vary_length)?)).unwrap_or_default();
+            let size = u64::from_le_bytes(reader.take(8)?.try_into().unwrap());
+            let expires = Duration::from_secs(u64::from_le_bytes(reader.take(8)?.try_into().unwrap()));
+
+            for (evicted, _) in index.insert(&path, IndexEntry::new(&key, tags, vary, size, expires)) { delete_file(evicted); }
+        }
+
+        index.dirty = false;
+        Ok(index)
+    }
+
+    // Serializes the index and clears the dirty flag; the caller writes it with write_index
+    // after releasing the lock.
+    pub fn snapshot(&mut self, folder: &Path) -> Vec<u8> {
+
+        let mut data = [INDEX_MAGIC.to_vec(), INDEX_VERSION.to_le_bytes().to_vec(), (self.entries.len() as u64).to_le_bytes().to_vec()].concat();
+
+        for path in self.recency.values() {
+
+            let entry = &self.entries[path];
+            let relative = path.strip_prefix(folder).unwrap_or(path).to_string_lossy().into_owned();
+
+            data.extend_from_slice(&(relative.len() as u16).to_le_bytes());
+            data.extend_from_slice(relative.as_bytes());
+            data.extend_from_slice(&(entry.key.len() as u32).to_le_bytes());
+            data.extend_from_slice(entry.key.as_bytes());
+            let tags = entry.tags.join(" ");
+            data.extend_from_slice(&(tags.len() as u32).to_le_bytes());
+            data.extend_from_slice(tags.as_bytes());
+            let vary = entry.vary.join(",");
+            data.extend_from_slice(&(vary.len() as u32).to_le_bytes());
+            data.extend_from_slice(vary.as_bytes());
+            data.extend_from_slice(&entry.size.to_le_bytes());
+            data.extend_from_slice(&entry.expires.as_secs().to_le_bytes());
+        }
+
+        data.extend_from_slice(&crc32::checksum(&data).to_le_bytes());
+
+        self.dirty = false;
+        data
+    }
+
+    pub fn mark_dirty(&mut self) {
+
+        self.dirty = true;
+    }
+
+    // Returns the path and key of every entry evicted to stay within the limits.
+    pub fn insert(&mut self, path: &Path, mut entry: IndexEntry) -> Vec<(PathBuf, String)> {
+
+        self.remove(path);
+
+        let primary = primary_key(&entry.key).to_string();
+
+        if entry.vary.is_empty() { self.variants.remove(&primary); }
+        else { self.variants.insert(primary, entry.vary.clone()); }
+
+        self.tick += 1;
+        entry.last_access = self.tick;
+        self.recency.insert(self.tick, path.to_path_buf());
+        self.expiry.insert((entry.expires, path.to_path_buf()));
+        self.bytes += entry.size;
+        self.entries.insert(path.to_path_buf(), entry);
+        self.dirty = true;
+
+        self.evict()
+    }
+
+    pub fn touch(&mut self, path: &Path) {
+
+        self.tick += 1;
+        let tick = self.tick;
+
+        if let Some(entry) = self.entries.get_mut(path) {
+
+            self.recency.remove(&entry.last_access);
+            self.recency.insert(tick, path.to_path_buf());
+            entry.last_access = tick;
+            self.dirty = true;
+        }
+    }
+
+    pub fn set_expires(&mut self, path: &Path, expires: Duration) {
+
+        if let Some(entry) = self.entries.get_mut(path) {
+
+            self.expiry.remove(&(entry.expires, path.to_path_buf()));
+            self.expiry.insert((expires, path.to_path_buf()));
+            entry.expires = expires;
+            self.dirty = true;
+        }
+    }
+
+    pub fn expired(&self, cutoff: Duration) -> Vec<(PathBuf, u64)> {
+
+        self.expiry
+            .iter()
+            .take_while(|(expires, _)| *expires <= cutoff)
+            .filter_map(|(_, path)| self.entries.get(path).map(|entry| (path.clone(), entry.size)))
+            .collect()
+    }
+
+    pub fn entries(&self) -> Vec<(PathBuf, IndexEntry)> {
+
+        self.entries.iter().map(|(path, entry)| (path.clone(), entry.clone())).collect()
+    }
+
+    pub fn is_verified(&self, path: &Path) -> bool {
+
+        self.entries.get(path).is_some_and(|entry| entry.verified)
+    }
+
+    pub fn set_verified(&mut self, path: &Path) {
+
+        if let Some(entry) = self.entries.get_mut(path) { entry.verified = true; }
+    }
+
+    pub fn vary_fields(&self, key: &str) -> Vec<String> {
+
+        self.variants.get(key).cloned().unwrap_or_default()
+    }
+
+    pub fn contains(&self, path: &Path) -> bool {
+
+        self.entries.contains_key(path)
+    }
+
+    pub fn remove(&mut self, path: &Path) -> Option<IndexEntry> {
+
+        let entry = self.entries.remove(path)?;
+
+        self.recency.remove(&entry.last_access);
+        self.expiry.remove(&(entry.expires, path.to_path_buf()));
+        self.bytes -= entry.size;
+        self.dirty = true;
+
+        Some(entry)
+    }
+
+    pub fn len(&self) -> usize {
+
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+
+        self.entries.is_empty()
+    }
+
+    pub fn bytes(&self) -> u64 {
+
+        self.bytes
+    }
+
+    pub fn is_dirty(&self) -> bool {
+
+        self.dirty
+    }
+
+    fn evict(&mut self) -> Vec<(PathBuf, String)> {
+
+        let mut evicted = Vec::new();
+
+        while self.bytes > self.max_bytes || self.entries.len() > self.max_entries {
+
+            let path = match self.recency.first_key_value() {
+
+                Some((_, path)) => { path.clone() },
+                None => { break },
+            };
+
+            if let Some(entry) = self.remove(&path) { evicted.push((path, entry.key)); }
+        }
+
+        evicted
+    }
+}
+
+struct IndexReader<'a> {
+
+    rest: &'a [u8],
+}
+
+impl<'a> IndexReader<'a> {
+
+    fn take(&mut self, length: usize) -> Result<&'a [u8], String> {
+
+        if self.rest.len() < length { return Err("Truncated cache index.".to_string()) }
+
+        let (value, rest) = self.rest.split_at(length);
+        self.rest = rest;
+
+        Ok(value)
+    }
+}
+
+pub fn write_index(folder: &Path, data: &[u8]) -> Result<(), String> {
+
+    let temp_path = folder.join(INDEX_FILE).with_extension("tmp");
+
+    let written = fs::create_dir_all(folder)
+        .and_then(|_| File::create(&temp_path))
+        .and_then(|mut file| file.write_all(data).and_then(|_| file.sync_all()))
+        .and_then(|_| fs::rename(&temp_path, folder.join(INDEX_FILE)));
+
+    match written {
+
+        Ok(_) => { Ok(()) },
+        Err(e) => { Err(e.to_string()) },
+    }
+}
+
+pub fn lock_index(index: &Mutex<CacheIndex>) -> MutexGuard<'_, CacheIndex> {
+
+    match index.lock() {
+
+        Ok(x) => { x },
+        Err(poisoned) => { poisoned.into_inner() },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::cache::testing::temp_path;
+
+    fn entry(key: &str, size: u64) -> IndexEntry {
+
+        IndexEntry::new(key, vec!["tag".to_string()], vec!["accept-language".to_string()], size, Duration::from_secs(100))
+    }
+
+    #[test]
+    fn round_trip() {
+
+        let folder = temp_path();
+        let mut index = CacheIndex::new(1000, 10);
+
+        index.insert(&folder.join("aa/1"), entry("GET http://localhost/a", 10));
+        index.insert(&folder.join("bb/2"), entry("GET http://localhost/b", 20));
+        index.touch(&folder.join("aa/1"));
+
+        write_index(&folder, &index.snapshot(&folder)).unwrap();
+        assert!(!index.is_dirty());
+
+        let loaded = CacheIndex::load(&folder, 1000, 10);
+        fs::remove_dir_all(&folder).unwrap();
+
+        let loaded = loaded.unwrap();
+        assert_eq!(loaded.len(), 2);
+        assert_eq!(loaded.bytes(), 30);
+        assert_eq!(loaded.vary_fields("GET http://localhost/a"), vec!["accept-language".to_string()]);
+        assert_eq!(loaded.expired(Duration::from_secs(100)).len(), 2);
+
+        let recency: Vec<&PathBuf> = loaded.recency.values().collect();
+        assert_eq!(recency, vec![&folder.join("bb/2"), &folder.join("aa/1")]);
+    }
+
+    #[test]
+    fn rejects_corrupt_index() {
+
+        let folder = temp_path();
+        let mut index = CacheIndex::new(1000, 10);
+        index.insert(&folder.join("aa/1"), entry("GET http://localhost/a", 10));
+
+        let mut data = index.snapshot(&folder);
+        let last = data.len() - 5;
+        data[last] ^= 0xff;
+        write_index(&folder, &data).unwrap();
+
+        let loaded = CacheIndex::load(&folder, 1000, 10);
+        fs::remove_dir_all(&folder).unwrap();
+
+        assert!(loaded.is_err());
+    }
+
+    #[test]
+    fn evicts_least_recently_used() {
+
+        let folder = temp_path();
+        let mut index = CacheIndex::new(25, 10);
+
+        assert!(index.insert(&folder.join("1"), entry("a", 10)).is_empty());
+        assert!(index.insert(&folder.join("2"), entry("b", 10)).is_empty());
+        index.touch(&folder.join("1"));
+
+        assert_eq!(index.insert(&folder.join("3"), entry("c", 10)), vec![(folder.join("2"), "b".to_string())]);
+        assert!(index.contains(&folder.join("1")));
+    }
+}
@@ -0,0 +1,161 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::Duration;
+
+pub struct InFlight<T> {
+
+    table: Mutex<HashMap<String, Arc<Slot<T>>>>,
+}
+
+pub struct Slot<T> {
+
+    result: Mutex<Option<Option<T>>>,
+    ready: Condvar,
+}
+
+pub enum Flight<'a, T> {
+
+    Leader(Leader<'a, T>),
+    Follower(Arc<Slot<T>>),
+}
+
+pub struct Leader<'a, T> {
+
+    inflight: &'a InFlight<T>,
+    key: String,
+    slot: Arc<Slot<T>>,
+}
+
+impl<T> Default for InFlight<T> {
+
+    fn default() -> Self {
+
+        InFlight { table: Mutex::new(HashMap::new()) }
+    }
+}
+
+impl<T: Clone> InFlight<T> {
+
+    pub fn join(&self, key: &str) -> Flight<'_, T> {
+
+        let mut table = match self.table.lock() {
+
+            Ok(x) => { x },
+            Err(poisoned) => { poisoned.into_inner() },
+        };
+
+        if let Some(slot) = table.get(key) { return Flight::Follower(Arc::clone(slot)) }
+
+        let slot = Arc::new(Slot { result: Mutex::new(None), ready: Condvar::new() });
+        table.insert(key.to_string(), Arc::clone(&slot));
+
+        Flight::Leader(Leader { inflight: self, key: key.to_string(), slot })
+    }
+}
+
+impl<T: Clone> Slot<T> {
+
+    pub fn wait(&self, timeout: Duration) -> Option<T> {
+
+        let result = match self.result.lock() {
+
+            Ok(x) => { x },
+            Err(poisoned) => { poisoned.into_inner() },
+        };
+
+        match self.ready.wait_timeout_while(result, timeout, |result| result.is_none()) {
+
+            Ok((result, _)) => { result.clone().flatten() },
+            Err(_) => { None },
+        }
+    }
+}
+
+impl<T> Leader<'_, T> {
+
+    pub fn complete(self, value: Option<T>) {
+
+        if let Ok(mut result) = self.slot.result.lock() { *result = Some(value); }
+    }
+}
+
+impl<T> Drop for Leader<'_, T> {
+
+    fn drop(&mut self) {
+
+        if let Ok(mut table) = self.inflight.table.lock() { table.remove(&self.key); }
+
+        if let Ok(mut result) = self.slot.result.lock() {
+
+            if result.is_none() { *result = Some(None); }
+        }
+
+        self.slot.ready.notify_all();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use std::thread;
+
+    fn leader<'a>(inflight: &'a InFlight<u32>, key: &str) -> Leader<'a, u32> {
+
+        match inflight.join(key) {
+
+            Flight::Leader(x) => { x },
+            Flight::Follower(_) => { panic!("expected to lead {key}") },
+        }
+    }
+
+    fn follower(inflight: &InFlight<u32>, key: &str) -> Arc<Slot<u32>> {
+
+        match inflight.join(key) {
+
+            Flight::Follower(x) => { x },
+            Flight::Leader(_) => { panic!("expected to follow {key}") },
+        }
+    }
+
+    #[test]
+    fn followers_receive_the_leader_result() {
+
+        let inflight = InFlight::default();
+        let first = leader(&inflight, "a");
+        let slot = follower(&inflight, "a");
+        let _other = leader(&inflight, "b");
+
+        let waiter = thread::spawn(move || slot.wait(Duration::from_secs(5)));
+        first.complete(Some(7));
+
+        assert_eq!(waiter.join().unwrap(), Some(7));
+
+        // The key is free again once the leader is done.
+        let _next = leader(&inflight, "a");
+    }
+
+    #[test]
+    fn followers_give_up_after_the_timeout() {
+
+        let inflight = InFlight::default();
+        let _first = leader(&inflight, "a");
+        let slot = follower(&inflight, "a");
+
+        assert_eq!(slot.wait(Duration::from_millis(10)), None);
+    }
+
+    #[test]
+    fn dropping_the_leader_releases_followers() {
+
+        let inflight = InFlight::default();
+        let first = leader(&inflight, "a");
+        let slot = follower(&inflight, "a");
+
+        let waiter = thread::spawn(move || slot.wait(Duration::from_secs(5)));
+        drop(first);
+
+        assert_eq!(waiter.join().unwrap(), None);
+        let _next = leader(&inflight, "a");
+    }
+}
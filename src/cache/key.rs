@@ -0,0 +1,243 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+use super::config::{CACHE_SCHEME, IGNORED_QUERY_PARAMS};
+
+const FNV_OFFSET: u128 = 0x6c62272e07bb014262b821756295c58d;
+const FNV_PRIME: u128 = 0x0000000001000000000000000000013b;
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct CacheKey {
+
+    pub method: String,
+    pub scheme: String,
+    pub host: String,
+    pub path: String,
+    pub query: String,
+}
+
+impl CacheKey {
+
+    pub fn new(method: &str, target: &str, headers: &HashMap<String, String>) -> Result<Self, String> {
+
+        let method = if method == "HEAD" { "GET".to_string() } else { method.to_uppercase() };
+
+        let (authority, target) = split_absolute_form(target);
+        let host = match (authority, headers.get("host")) {
+
+            (Some(authority), _) => { normalize_host(authority) },
+            (None, Some(host)) => { normalize_host(host) },
+            (None, None) => { String::new() },
+        };
+
+        let target = target.split('#').next().unwrap_or("");
+        let (path, query) = match target.split_once('?') {
+
+            Some((path, query)) => { (path, query) },
+            None => { (target, "") },
+        };
+
+        let path = if path.is_empty() { "/".to_string() } else { normalize_percent_encoding(path) };
+        let path = match remove_dot_segments(&path) {
+
+            Some(path) => { path },
+            None => { return Err(format!("Request path escapes the root. path: {path:?}")) },
+        };
+
+        Ok(CacheKey {
+
+            method,
+            scheme: CACHE_SCHEME.to_string(),
+            host,
+            path,
+            query: normalize_query(query),
+        })
+    }
+
+    pub fn url(&self) -> String {
+
+        if self.query.is_empty() { format!("{}://{}{}", self.scheme, self.host, self.path) }
+        else { format!("{}://{}{}?{}", self.scheme, self.host, self.path, self.query) }
+    }
+}
+
+impl fmt::Display for CacheKey {
+
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+
+        write!(f, "{} {}", self.method, self.url())
+    }
+}
+
+pub fn hash_key(key: &str) -> String {
+
+    let mut hash = FNV_OFFSET;
+
+    for byte in key.as_bytes() {
+
+        hash ^= *byte as u128;
+        hash = hash.wrapping_mul(FNV_PRIME);
+    }
+
+    format!("{hash:032x}")
+}
+
+pub fn key_path(cache_folder: &Path, key: &str) -> PathBuf {
+
+    let hash = hash_key(key);
+    let mut path = cache_folder.to_path_buf();
+    path.push(&hash[..2]);
+    path.push(hash);
+
+    path
+}
+
+fn split_absolute_form(target: &str) -> (Option<&str>, &str) {
+
+    let rest = match target.split_once("://") {
+
+        Some((scheme, rest)) if !scheme.contains('/') => { rest },
+        _ => { return (None, target) },
+    };
+
+    match rest.find('/') {
+
+        Some(index) => { (Some(&rest[..index]), &rest[index..]) },
+        None => { (Some(rest), "/") },
+    }
+}
+
+fn normalize_host(host: &str) -> String {
+
+    let host = host.trim().to_lowercase();
+
+    match host.strip_suffix(":80") {
+
+        Some(stripped) => { stripped.to_string() },
+        None => { host },
+    }
+}
+
+fn normalize_query(query: &str) -> String {
+
+    let mut params: Vec<String> = query
+        .split('&')
+        .filter(|param| !param.is_empty())
+        .filter(|param| {
+            let name = param.split('=').next().unwrap_or("");
+            !IGNORED_QUERY_PARAMS.contains(&name)
+        })
+        .map(normalize_percent_encoding)
+        .collect();
+
+    params.sort();
+    params.join("&")
+}
+
+fn normalize_percent_encoding(component: &str) -> String {
+
+    let bytes = component.as_bytes();
+    let mut normalized = Vec::with_capacity(bytes.len());
+    let mut index = 0;
+
+    while index < bytes.len() {
+
+        if bytes[index] == b'%' && index + 2 < bytes.len() && is_hex_pair(&bytes[index + 1..index + 3]) {
+
+            let hex = bytes[index + 1..index + 3].to_ascii_uppercase();
+            let value = u8::from_str_radix(&String::from_utf8_lossy(&hex), 16).unwrap_or(0);
+
+            if value.is_ascii_alphanumeric() || b"-._~".contains(&value) { normalized.push(value); }
+            else { normalized.push(b'%'); normalized.extend_from_slice(&hex); }
+
+            index += 3;
+        } else {
+
+            normalized.push(bytes[index]);
+            index += 1;
+        }
+    }
+
+    String::from_utf8_lossy(&normalized).to_string()
+}
+
+fn remove_dot_segments(path: &str) -> Option<String> {
+
+    let mut output: Vec<&str> = Vec::new();
+    let segments: Vec<&str> = path.split(['/', '\\']).collect();
+
+    for (index, segment) in segments.iter().enumerate() {
+
+        let last = index == segments.len() - 1;
+
+        match *segment {
+
+            "." => { if last { output.push(""); } },
+            ".." => {
+
+                if output.len() <= 1 { return None; }
+                output.pop();
+                if last { output.push(""); }
+            },
+            _ => { output.push(segment); },
+        }
+    }
+
+    let normalized = output.join("/");
+
+    if normalized.starts_with('/') { Some(normalized) } else { Some(format!("/{normalized}")) }
+}
+
+fn is_hex_pair(bytes: &[u8]) -> bool {
+
+    bytes.iter().all(|byte| byte.is_ascii_hexdigit())
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    fn key(method: &str, target: &str) -> Result<String, String> {
+
+        let headers = HashMap::from([("host".to_string(), "Example.COM:80".to_string())]);
+
+        CacheKey::new(method, target, &headers).map(|key| key.to_string())
+    }
+
+    #[test]
+    fn normalizes_method_and_host() {
+
+        assert_eq!(key("HEAD", "/a").unwrap(), "GET http://example.com/a");
+        assert_eq!(key("get", "").unwrap(), "GET http://example.com/");
+        assert_eq!(key("GET", "http://Other.org/b#frag").unwrap(), "GET http://other.org/b");
+    }
+
+    #[test]
+    fn normalizes_query_parameters() {
+
+        assert_eq!(key("GET", "/a?b=2&a=1&utm_source=x&").unwrap(), "GET http://example.com/a?a=1&b=2");
+        assert_eq!(key("GET", "/a?utm_campaign=x").unwrap(), "GET http://example.com/a");
+    }
+
+    #[test]
+    fn normalizes_paths() {
+
+        assert_eq!(key("GET", "/%7euser/%2f%41").unwrap(), "GET http://example.com/~user/%2FA");
+        assert_eq!(key("GET", "/a/./b/../c").unwrap(), "GET http://example.com/a/c");
+        assert_eq!(key("GET", "/a\\b").unwrap(), "GET http://example.com/a/b");
+        assert!(key("GET", "/../etc/passwd").is_err());
+    }
+
+    #[test]
+    fn key_paths_stay_in_the_folder() {
+
+        let folder = Path::new("/cache");
+        let path = key_path(folder, "GET http://example.com/../../a");
+
+        assert!(path.starts_with(folder));
+        assert_eq!(path.components().count(), 4);
+        assert_eq!(path, key_path(folder, "GET http://example.com/../../a"));
+    }
+}
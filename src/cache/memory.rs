@@ -0,0 +1,321 @@
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Mutex, MutexGuard};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use super::filedata::FileData;
+use super::metadata::Metadata;
+use super::store::{CacheStore, StoreEntry, StoreStats};
+use super::vary::{primary_key, response_vary_fields};
+
+struct Entry {
+
+    filedata: FileData,
+    size: u64,
+    tick: u64,
+}
+
+#[derive(Default)]
+struct Lru {
+
+    entries: HashMap<String, Entry>,
+    order: BTreeMap<u64, String>,
+    variants: HashMap<String, Vec<String>>,
+    bytes: u64,
+    tick: u64,
+}
+
+pub struct MemoryStore {
+
+    budget: u64,
+    max_object: u64,
+    lru: Mutex<Lru>,
+}
+
+impl MemoryStore {
+
+    pub fn new(budget: u64, max_object: u64) -> Self {
+
+        MemoryStore { budget, max_object, lru: Mutex::new(Lru::default()) }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Lru> {
+
+        match self.lru.lock() {
+
+            Ok(x) => { x },
+            Err(poisoned) => { poisoned.into_inner() },
+        }
+    }
+}
+
+impl Lru {
+
+    fn touch(&mut self, key: &str) -> Option<&Entry> {
+
+        self.tick += 1;
+        let tick = self.tick;
+        let entry = self.entries.get_mut(key)?;
+
+        self.order.remove(&entry.tick);
+        self.order.insert(tick, key.to_string());
+        entry.tick = tick;
+
+        Some(entry)
+    }
+
+    fn remove(&mut self, key: &str) -> bool {
+
+        match self.entries.remove(key) {
+
+            Some(entry) => {
+
+                self.order.remove(&entry.tick);
+                self.bytes -= entry.size;
+                true
+            },
+            None => { false },
+        }
+    }
+
+    fn evict_to(&mut self, budget: u64) {
+
+        while self.bytes > budget {
+
+            let key = match self.order.first_key_value() {
+
+                Some((_, key)) => { key.clone() },
+                None => { return },
+            };
+
+            self.remove(&key);
+        }
+    }
+}
+
+impl CacheStore for MemoryStore {
+
+    fn metadata(&self, key: &str) -> Result<Metadata, String> {
+
+        match self.lock().entries.get(key) {
+
+            Some(entry) => { Ok(entry.filedata.metadata.clone()) },
+            None => { Err("Not in memory cache.".to_string()) },
+        }
+    }
+
+    fn read(&self, key: &str, _metadata: Metadata) -> Result<FileData, String> {
+
+        match self.lock().touch(key).and_then(|entry| entry.filedata.share()) {
+
+            Some(x) => { Ok(x) },
+            None => { Err("Not in memory cache.".to_string()) },
+        }
+    }
+
+    fn write(&self, filedata: &FileData) -> bool {
+
+        let size = filedata.metadata.get_size() + filedata.metadata.content_length;
+        if size > self.max_object || size > self.budget { return false }
+
+        let filedata = match filedata.share() {
+
+            Some(x) => { x },
+            None => { return false },
+        };
+
+        let headers: HashMap<String, String> = filedata.metadata.get_headers().iter().cloned().collect();
+        let fields = response_vary_fields(&headers).unwrap_or_default();
+
+        let key = filedata.metadata.key.clone();
+        let primary = primary_key(&key).to_string();
+
+        let mut lru = self.lock();
+        lru.remove(&key);
+
+        if fields.is_empty() { lru.variants.remove(&primary); }
+        else { lru.variants.insert(primary, fields); }
+
+        lru.tick += 1;
+        let tick = lru.tick;
+        lru.order.insert(tick, key.clone());
+        lru.entries.insert(key, Entry { filedata, size, tick });
+        lru.bytes += size;
+
+        let budget = self.budget;
+        lru.evict_to(budget);
+
+        true
+    }
+
+    fn refresh(&self, key: &str, metadata: &mut Metadata, ttl: Duration) -> Result<(), String> {
+
+        let date = match SystemTime::now().duration_since(UNIX_EPOCH) {
+
+            Ok(x) => { x },
+            Err(e) => { return Err(e.to_string()) },
+        };
+
+        match self.lock().entries.get_mut(key) {
+
+            Some(entry) => {
+
+                entry.filedata.metadata.creation_date = date;
+                entry.filedata.metadata.ttl = ttl;
+            },
+            None => { return Err("Not in memory cache.".to_string()) },
+        }
+
+        metadata.creation_date = date;
+        metadata.ttl = ttl;
+
+        Ok(())
+    }
+
+    fn delete(&self, key: &str) -> bool {
+
+        self.lock().remove(key)
+    }
+
+    fn vary_fields(&self, key: &str) -> Vec<String> {
+
+        self.lock().variants.get(key).cloned().unwrap_or_default()
+    }
+
+    fn entries(&self) -> Vec<StoreEntry> {
+
+        self.lock()
+            .entries
+            .iter()
+            .map(|(key, entry)| {
+
+                let metadata = &entry.filedata.metadata;
+                StoreEntry { key: key.clone(), tags: metadata.get_surrogate_keys(), size: entry.size, expires: metadata.expires() }
+            })
+            .collect()
+    }
+
+    fn stats(&self) -> StoreStats {
+
+        let lru = self.lock();
+
+        StoreStats { entries: lru.entries.len(), bytes: lru.bytes }
+    }
+
+    fn remove_expired(&self, cutoff: Duration) -> StoreStats {
+
+        let mut lru = self.lock();
+        let mut reclaimed = StoreStats::default();
+
+        let expired: Vec<(String, u64)> = lru
+            .entries
+            .iter()
+            .filter(|(_, entry)| entry.filedata.metadata.expires() <= cutoff)
+            .map(|(key, entry)| (key.clone(), entry.size))
+            .collect();
+
+        for (key, size) in expired {
+
+            if lru.remove(&key) {
+
+                reclaimed.entries += 1;
+                reclaimed.bytes += size;
+            }
+        }
+
+        reclaimed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::cache::testing::filedata;
+
+    fn size(filedata: &FileData) -> u64 {
+
+        filedata.metadata.get_size() + filedata.metadata.content_length
+    }
+
+    #[test]
+    fn writes_and_reads_entries() {
+
+        let store = MemoryStore::new(1 << 20, 1 << 20);
+        let a = filedata("GET http://localhost/a", b"hello", &[("surrogate-key", "t1 t2")]);
+
+        assert!(store.write(&a));
+
+        let metadata = store.metadata("GET http://localhost/a").unwrap();
+        let read = store.read("GET http://localhost/a", metadata).unwrap();
+        assert_eq!(read.get_content(), Some(&b"hello"[..]));
+        assert!(store.metadata("GET http://localhost/b").is_err());
+
+        let entries = store.entries();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].key, "GET http://localhost/a");
+        assert_eq!(entries[0].tags, vec!["t1".to_string(), "t2".to_string()]);
+        assert_eq!(store.stats(), StoreStats { entries: 1, bytes: size(&a) });
+
+        assert!(store.delete("GET http://localhost/a"));
+        assert!(!store.delete("GET http://localhost/a"));
+        assert_eq!(store.stats(), StoreStats::default());
+    }
+
+    #[test]
+    fn rejects_objects_over_the_limits() {
+
+        let a = filedata("GET http://localhost/a", &[0; 64], &[]);
+
+        assert!(!MemoryStore::new(1 << 20, size(&a) - 1).write(&a));
+        assert!(!MemoryStore::new(size(&a) - 1, 1 << 20).write(&a));
+    }
+
+    #[test]
+    fn evicts_least_recently_used() {
+
+        let a = filedata("GET http://localhost/a", &[0; 64], &[]);
+        let b = filedata("GET http://localhost/b", &[0; 64], &[]);
+        let c = filedata("GET http://localhost/c", &[0; 64], &[]);
+        let store = MemoryStore::new(size(&a) + size(&b) + size(&c) - 1, 1 << 20);
+
+        store.write(&a);
+        store.write(&b);
+        store.read("GET http://localhost/a", a.metadata.clone()).unwrap();
+        store.write(&c);
+
+        assert!(store.metadata("GET http://localhost/a").is_ok());
+        assert!(store.metadata("GET http://localhost/b").is_err());
+        assert!(store.metadata("GET http://localhost/c").is_ok());
+    }
+
+    #[test]
+    fn tracks_vary_fields_by_primary_key() {
+
+        let store = MemoryStore::new(1 << 20, 1 << 20);
+
+        store.write(&filedata("GET http://localhost/a\naccept-language: en", b"en", &[("vary", "Accept-Language")]));
+        assert_eq!(store.vary_fields("GET http://localhost/a"), vec!["accept-language".to_string()]);
+
+        store.write(&filedata("GET http://localhost/a", b"any", &[]));
+        assert!(store.vary_fields("GET http://localhost/a").is_empty());
+    }
+
+    #[test]
+    fn refreshes_and_expires_entries() {
+
+        let store = MemoryStore::new(1 << 20, 1 << 20);
+        let a = filedata("GET http://localhost/a", b"a", &[]);
+        store.write(&a);
+
+        let mut metadata = a.metadata.clone();
+        store.refresh("GET http://localhost/a", &mut metadata, Duration::from_secs(600)).unwrap();
+        assert_eq!(metadata.ttl, Duration::from_secs(600));
+        assert_eq!(store.metadata("GET http://localhost/a").unwrap().ttl, Duration::from_secs(600));
+        assert!(store.refresh("GET http://localhost/b", &mut metadata, Duration::ZERO).is_err());
+
+        let expires = store.entries()[0].expires;
+        assert_eq!(store.remove_expired(expires - Duration::from_secs(1)), StoreStats::default());
+        assert_eq!(store.remove_expired(expires).entries, 1);
+        assert!(store.metadata("GET http://localhost/a").is_err());
+    }
+}
@@ -1,22 +1,69 @@
+//! On-disk layout of a cache entry (all integers little-endian):
+//!
+//! | offset | size | field                                     |
+//! |--------|------|-------------------------------------------|
+//! | 0      | 4    | magic `RPLC`                              |
+//! | 4      | 2    | format version                            |
+//! | 6      | 2    | flags, reserved (0)                       |
+//! | 8      | 8    | creation date, seconds since UNIX epoch   |
+//! | 16     | 8    | ttl, seconds                              |
+//! | 24     | 8    | body length                               |
+//! | 32     | 4    | CRC32 (IEEE) of the body                  |
+//! | 36     | 2    | response status code                      |
+//! | 38     | 4    | extension block length `N`                |
+//! | 42     | N    | extension records                         |
+//! | 42 + N | ...  | body                                      |
+//!
+//! Each extension record is `[u16 tag][u32 length][length bytes]`: tag 1 holds the stored
+//! response headers and tag 2 the variant key. Readers skip tags they
+//! do not know, so new fields can be added without bumping the version. Files with another
+//! magic or version, including the unversioned layout used before, are discarded on read.
+
+use std::collections::HashMap;
 use std::time::{SystemTime, Duration, UNIX_EPOCH};
-use std::fs::File;
+use std::fs::{File, OpenOptions};
 use std::path::Path;
-use std::io::{prelude::*, BufReader};
-
-pub const METADATA_SPLIT_SIZE: usize = std::mem::size_of::<u64>();
-
-#[derive(Debug)]
+use std::io::{prelude::*, BufReader, SeekFrom};
+
+use super::policy::parse_delta_seconds;
+
+pub const MAGIC: [u8; 4] = *b"RPLC";
+pub const FORMAT_VERSION: u16 = 1;
+pub const OFFSET_CREATION_DATE: u64 = 8;
+pub const PREAMBLE_SIZE: usize = 42;
+
+pub const EXT_HEADERS: u16 = 1;
+pub const EXT_KEY: u16 = 2;
+
+const HOP_BY_HOP: [&str; 10] = [
+    "connection",
+    "keep-alive",
+    "proxy-authenticate",
+    "proxy-authorization",
+    "proxy-connection",
+    "te",
+    "trailer",
+    "transfer-encoding",
+    "upgrade",
+    "content-length",
+];
+
+#[derive(Debug, Clone)]
 pub struct Metadata {
 
     pub creation_date: Duration,
     pub ttl: Duration,
     pub content_length: u64,
-    pub content_type: Option<String>,
+    pub checksum: u32,
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    pub key: String,
+    pub header_size: u64,
 }
 
 impl Metadata {
 
-    pub fn default(ttl: u64, content_length: u64, content_type: Option<String>) -> Result<Self, String> {
+    pub fn default(ttl: u64, content_length: u64, status: u16, headers: &HashMap<String, String>) -> Result<Self, String> {
 
         let date = match SystemTime::now().duration_since(UNIX_EPOCH) {
 
@@ -29,7 +76,11 @@ impl Metadata {
             creation_date: date,
             ttl: Duration::from_secs(ttl),
             content_length,
-            content_type,
+            checksum: 0,
+            status,
+            headers: end_to_end_headers(headers),
+            key: String::new(),
+            header_size: 0,
         })
     }
 
@@ -37,7 +88,7 @@ impl Metadata {
 
         match SystemTime::now().duration_since(UNIX_EPOCH) {
 
-            Ok(x) => { x >= self.creation_date + self.ttl },
+            Ok(x) => { x >= self.expires() },
             Err(_) => { false },
         }
     }
@@ -50,48 +101,129 @@ impl Metadata {
             Err(x) => { return Err(x.to_string()) },
         };
 
+        let file_size = match file.metadata() {
+
+            Ok(x) => { x.len() },
+            Err(e) => { return Err(e.to_string()) },
+        };
+
         let mut bf_reader = BufReader::new(file);
-        let mut buffer = [0u8; std::mem::size_of::<u64>() * 3]; //metadata_split_size
-        let mut content_type = String::new();
+        let mut buffer = [0u8; PREAMBLE_SIZE];
 
-        match bf_reader.read_line(&mut content_type) {
+        match bf_reader.read_exact(&mut buffer) {
 
             Ok(_) => {},
             Err(e) => { return Err(e.to_string()) },
         }
 
-        match bf_reader.read_exact(&mut buffer) {
+        let preamble = parse_tools(&buffer)?;
+
+        if preamble.extensions_length as u64 > file_size.saturating_sub(PREAMBLE_SIZE as u64) { return Err("Extension block longer than the cache file.".to_string()) }
+
+        let mut extensions = vec![0u8; preamble.extensions_length as usize];
+
+        match bf_reader.read_exact(&mut extensions) {
 
             Ok(_) => {},
             Err(e) => { return Err(e.to_string()) },
         }
 
-        Ok(Self::parse_buffer(&buffer, content_type))
+        let mut metadata = Metadata {
+
+            creation_date: preamble.creation_date,
+            ttl: preamble.ttl,
+            content_length: preamble.content_length,
+            checksum: preamble.checksum,
+            status: preamble.status,
+            headers: Vec::new(),
+            key: String::new(),
+            header_size: (PREAMBLE_SIZE + extensions.len()) as u64,
+        };
+
+        for (tag, value) in parse_extensions(&extensions)? {
+
+            match tag {
+
+                EXT_HEADERS => { metadata.headers = parse_headers(&String::from_utf8_lossy(value)); },
+                EXT_KEY => { metadata.key = String::from_utf8_lossy(value).to_string(); },
+                _ => {},
+            }
+        }
+
+        Ok(metadata)
     }
 
-    pub fn parse_buffer(buffer: &[u8], content_type: String) -> Self {
+    pub fn refresh_file(&mut self, path: &Path, ttl: Duration) -> Result<(), String> {
 
-        assert!(buffer.len() == std::mem::size_of::<u64>() * 3);
+        let date = match SystemTime::now().duration_since(UNIX_EPOCH) {
 
-        let content_type = if content_type.is_empty() { None} else { Some(content_type.trim().to_string()) };
+            Ok(x) => { x },
+            Err(e) => { return Err(e.to_string()) },
+        };
 
-        let (date, ttl_secs, content_length) = parse_tools(buffer);
+        let mut file = match OpenOptions::new().write(true).open(path) {
 
-        Metadata {
-            creation_date: date,
-            ttl: ttl_secs,
-            content_length,
-            content_type,
+            Ok(file) => { file },
+            Err(e) => { return Err(e.to_string()) },
+        };
+
+        let fields = [date.as_secs().to_le_bytes(), ttl.as_secs().to_le_bytes()].concat();
+
+        match file.seek(SeekFrom::Start(OFFSET_CREATION_DATE)).and_then(|_| file.write_all(&fields)) {
+
+            Ok(_) => {},
+            Err(e) => { return Err(e.to_string()) },
+        }
+
+        self.creation_date = date;
+        self.ttl = ttl;
+
+        Ok(())
+    }
+
+    pub fn expires(&self) -> Duration {
+
+        self.creation_date.saturating_add(self.ttl)
+    }
+
+    pub fn get_staleness(&self) -> Duration {
+
+        match SystemTime::now().duration_since(UNIX_EPOCH) {
+
+            Ok(x) => { x.saturating_sub(self.expires()) },
+            Err(_) => { Duration::ZERO },
         }
     }
 
     pub fn get_size(&self) -> u64 {
 
-        match &self.content_type {
+        if self.header_size > 0 { self.header_size } else { (PREAMBLE_SIZE + self.format_extensions().len()) as u64 }
+    }
 
-            Some(ct) => { ((METADATA_SPLIT_SIZE * 3) + ct.as_bytes().len() + 1) as u64 },
-            None => { ((METADATA_SPLIT_SIZE * 3) + 1) as u64 },
-        }
+    pub fn format_preamble(&self) -> Vec<u8> {
+
+        let extensions_length = self.format_extensions().len() as u32;
+
+        [
+            MAGIC.to_vec(),
+            FORMAT_VERSION.to_le_bytes().to_vec(),
+            0u16.to_le_bytes().to_vec(),
+            self.creation_date.as_secs().to_le_bytes().to_vec(),
+            self.ttl.as_secs().to_le_bytes().to_vec(),
+            self.content_length.to_le_bytes().to_vec(),
+            self.checksum.to_le_bytes().to_vec(),
+            self.status.to_le_bytes().to_vec(),
+            extensions_length.to_le_bytes().to_vec(),
+        ].concat()
+    }
+
+    pub fn format_extensions(&self) -> Vec<u8> {
+
+        let mut extensions = format_extension(EXT_HEADERS, &self.format_headers());
+
+        if !self.key.is_empty() { extensions.extend(format_extension(EXT_KEY, self.key.as_bytes())); }
+
+        extensions
     }
 
     pub fn get_creation_date(&self) -> Duration {
@@ -109,20 +241,224 @@ impl Metadata {
         self.content_length
     }
 
-    pub fn get_content_type(&self) -> &Option<String> {
+    pub fn get_status(&self) -> u16 {
+
+        self.status
+    }
+
+    pub fn get_headers(&self) -> &Vec<(String, String)> {
+
+        &self.headers
+    }
+
+    pub fn get_header(&self, name: &str) -> Option<&String> {
+
+        self.headers.iter().find(|(k, _)| k == name).map(|(_, v)| v)
+    }
+
+    pub fn get_surrogate_keys(&self) -> Vec<String> {
+
+        match self.get_header("surrogate-key") {
+
+            Some(x) => { x.split_whitespace().map(String::from).collect() },
+            None => { Vec::new() },
+        }
+    }
+
+    pub fn get_content_type(&self) -> Option<&String> {
+
+        self.get_header("content-type")
+    }
+
+    pub fn get_age(&self) -> Duration {
+
+        let initial_age = match self.get_header("age").and_then(|age| parse_delta_seconds(age)) {
+
+            Some(secs) => { Duration::from_secs(secs) },
+            None => { Duration::ZERO },
+        };
+
+        match SystemTime::now().duration_since(UNIX_EPOCH) {
+
+            Ok(x) => { initial_age.saturating_add(x.saturating_sub(self.creation_date)) },
+            Err(_) => { initial_age },
+        }
+    }
+
+    pub fn format_headers(&self) -> Vec<u8> {
+
+        let mut block = String::new();
+
+        for (key, value) in &self.headers {
+
+            block.push_str(&format!("{key}: {value}\r\n"));
+        }
+
+        block.into_bytes()
+    }
+}
+
+pub fn end_to_end_headers(headers: &HashMap<String, String>) -> Vec<(String, String)> {
+
+    let listed: Vec<String> = match headers.get("connection") {
 
-        &self.content_type
+        Some(connection) => { connection.split(',').map(|name| name.trim().to_lowercase()).collect() },
+        None => { Vec::new() },
+    };
+
+    let mut stored: Vec<(String, String)> = headers
+        .iter()
+        .filter(|(k, _)| !HOP_BY_HOP.contains(&k.as_str()) && !listed.contains(k))
+        .map(|(k, v)| (k.clone(), v.clone()))
+        .collect();
+
+    stored.sort();
+    stored
+}
+
+fn parse_headers(block: &str) -> Vec<(String, String)> {
+
+    block
+        .split("\r\n")
+        .filter_map(|line| line.split_once(':'))
+        .map(|(k, v)| (k.trim().to_lowercase(), v.trim().to_string()))
+        .collect()
+}
+
+pub fn format_extension(tag: u16, value: &[u8]) -> Vec<u8> {
+
+    [tag.to_le_bytes().to_vec(), (value.len() as u32).to_le_bytes().to_vec(), value.to_vec()].concat()
+}
+
+fn parse_extensions(block: &[u8]) -> Result<Vec<(u16, &[u8])>, String> {
+
+    let mut extensions = Vec::new();
+    let mut rest = block;
+
+    while !rest.is_empty() {
+
+        if rest.len() < 6 { return Err("Truncated extension record in cache file.".to_string()) }
+
+        let tag = u16::from_le_bytes([rest[0], rest[1]]);
+        let length = u32::from_le_bytes([rest[2], rest[3], rest[4], rest[5]]) as usize;
+
+        if rest.len() < 6 + length { return Err("Truncated extension record in cache file.".to_string()) }
+
+        extensions.push((tag, &rest[6..6 + length]));
+        rest = &rest[6 + length..];
     }
+
+    Ok(extensions)
 }
 
-pub fn parse_tools(buffer: &[u8]) -> (Duration, Duration, u64) {
+pub struct Preamble {
 
-    let (a, b) = buffer.split_at(METADATA_SPLIT_SIZE);
-    let (b, c) = b.split_at(METADATA_SPLIT_SIZE);
+    pub creation_date: Duration,
+    pub ttl: Duration,
+    pub content_length: u64,
+    pub checksum: u32,
+    pub status: u16,
+    pub extensions_length: u32,
+}
+
+pub fn parse_tools(buffer: &[u8]) -> Result<Preamble, String> {
+
+    assert!(buffer.len() == PREAMBLE_SIZE);
+
+    if buffer[0..4] != MAGIC { return Err("Not a cache file: bad magic number.".to_string()) }
+
+    let version = u16::from_le_bytes(buffer[4..6].try_into().unwrap());
+    if version != FORMAT_VERSION { return Err(format!("Unsupported cache file version {version}.")) }
+
+    Ok(Preamble {
+
+        creation_date: Duration::from_secs(u64::from_le_bytes(buffer[8..16].try_into().unwrap())),
+        ttl: Duration::from_secs(u64::from_le_bytes(buffer[16..24].try_into().unwrap())),
+        content_length: u64::from_le_bytes(buffer[24..32].try_into().unwrap()),
+        checksum: u32::from_le_bytes(buffer[32..36].try_into().unwrap()),
+        status: u16::from_le_bytes(buffer[36..38].try_into().unwrap()),
+        extensions_length: u32::from_le_bytes(buffer[38..42].try_into().unwrap()),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::cache::testing::{metadata, temp_path};
+
+    fn temp_file(contents: &[u8]) -> std::path::PathBuf {
+
+        let path = temp_path();
+        std::fs::write(&path, contents).unwrap();
+        path
+    }
+
+    #[test]
+    fn header_round_trip() {
+
+        let mut original = metadata(200, 0, &[("etag", "\"v1\""), ("connection", "close")]);
+        original.key = "GET http://localhost/a".to_string();
+        original.content_length = 3;
+
+        let mut contents = [original.format_preamble(), original.format_extensions()].concat();
+        contents.extend(format_extension(99, b"unknown"));
+        let extensions_length = (contents.len() - PREAMBLE_SIZE) as u32;
+        contents[38..42].copy_from_slice(&extensions_length.to_le_bytes());
+        contents.extend(b"abc");
+
+        let path = temp_file(&contents);
+        let parsed = Metadata::parse_file(&path);
+        std::fs::remove_file(&path).unwrap();
+
+        let parsed = parsed.unwrap();
+        assert_eq!(parsed.key, original.key);
+        assert_eq!(parsed.get_header("etag"), Some(&"\"v1\"".to_string()));
+        assert_eq!(parsed.get_header("connection"), None);
+        assert_eq!(parsed.content_length, 3);
+        assert_eq!(parsed.get_size(), (contents.len() - 3) as u64);
+    }
+
+    #[test]
+    fn rejects_corrupt_headers() {
+
+        let valid = metadata(200, 0, &[]).format_preamble();
+
+        let mut bad_magic = valid.clone();
+        bad_magic[0] = b'X';
+
+        let mut bad_version = valid.clone();
+        bad_version[4] = 0xff;
 
-    let date_parse = Duration::from_secs(u64::from_le_bytes(a.try_into().unwrap()));
-    let ttl_parse = Duration::from_secs(u64::from_le_bytes(b.try_into().unwrap()));
-    let cl_parse = u64::from_le_bytes(c.try_into().unwrap());
+        let mut huge_extensions = valid.clone();
+        huge_extensions[38..42].copy_from_slice(&u32::MAX.to_le_bytes());
 
-    (date_parse, ttl_parse, cl_parse)
+        for contents in [bad_magic, bad_version, huge_extensions, valid[..20].to_vec()] {
+
+            let path = temp_file(&contents);
+            let parsed = Metadata::parse_file(&path);
+            std::fs::remove_file(&path).unwrap();
+
+            assert!(parsed.is_err());
+        }
+    }
+
+    #[test]
+    fn expiry_saturates_instead_of_overflowing() {
+
+        let mut metadata = metadata(200, 0, &[]);
+        metadata.ttl = Duration::from_secs(u64::MAX);
+
+        assert_eq!(metadata.expires(), Duration::MAX);
+        assert!(!metadata.ttl_check());
+        assert_eq!(metadata.get_staleness(), Duration::ZERO);
+    }
+
+    #[test]
+    fn huge_age_header_does_not_overflow() {
+
+        let metadata = metadata(200, 0, &[("age", "18446744073709551615")]);
+
+        assert!(metadata.get_age() >= Duration::from_secs(1 << 31));
+    }
 }
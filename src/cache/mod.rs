@@ -1,3 +1,20 @@
+pub mod conditional;
+pub mod config;
+pub mod crc32;
+pub mod disk;
 pub mod filedata;
+pub mod httpdate;
+pub mod index;
+pub mod inflight;
+pub mod key;
+pub mod memory;
 pub mod metadata;
-pub mod utils;
\ No newline at end of file
+pub mod policy;
+pub mod purge;
+pub mod range;
+pub mod store;
+#[cfg(test)]
+pub mod testing;
+pub mod utils;
+pub mod vary;
+pub mod writer;
\ No newline at end of file
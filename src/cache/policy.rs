@@ -0,0 +1,238 @@
+use std::collections::HashMap;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use super::config::{CACHEABLE_METHODS, CACHEABLE_STATUS, CACHE_AUTHORIZED, CACHE_SET_COOKIE, HEURISTIC_PERCENT, HEURISTIC_MAX_TTL, NEGATIVE_CACHE_ENABLED, NEGATIVE_CACHE_TTL};
+use super::httpdate::parse_http_date;
+use super::metadata::Metadata;
+use super::vary::response_vary_fields;
+
+const DELTA_SECONDS_MAX: u64 = 1 << 31;
+
+#[derive(Debug, Default)]
+pub struct CacheControl {
+
+    directives: HashMap<String, Option<String>>,
+}
+
+impl CacheControl {
+
+    pub fn parse(headers: &HashMap<String, String>, name: &str) -> Self {
+
+        Self::parse_value(headers.get(name))
+    }
+
+    pub fn parse_value(value: Option<&String>) -> Self {
+
+        let mut directives = HashMap::new();
+
+        if let Some(value) = value {
+
+            for directive in value.split(',') {
+
+                let directive = directive.trim();
+                if directive.is_empty() { continue; }
+
+                match directive.split_once('=') {
+
+                    Some((k, v)) => { directives.insert(k.trim().to_lowercase(), Some(v.trim().trim_matches('"').to_string())); },
+                    None => { directives.insert(directive.to_lowercase(), None); },
+                }
+            }
+        }
+
+        CacheControl { directives }
+    }
+
+    pub fn has(&self, directive: &str) -> bool {
+
+        self.directives.contains_key(directive)
+    }
+
+    pub fn seconds(&self, directive: &str) -> Option<u64> {
+
+        match self.directives.get(directive) {
+
+            Some(Some(value)) => { parse_delta_seconds(value) },
+            _ => { None },
+        }
+    }
+}
+
+// RFC 9111 §1.2.2: delta-seconds larger than 2^31 are treated as 2^31.
+pub fn parse_delta_seconds(value: &str) -> Option<u64> {
+
+    let value = value.trim();
+
+    if value.is_empty() || !value.bytes().all(|byte| byte.is_ascii_digit()) { return None }
+
+    Some(value.parse::<u64>().unwrap_or(u64::MAX).min(DELTA_SECONDS_MAX))
+}
+
+// Request-side half of is_storable: false means no response to this request can be stored.
+pub fn may_store_request(method: &str, request: &HashMap<String, String>) -> bool {
+
+    if !CACHEABLE_METHODS.contains(&method) { return false }
+    if CacheControl::parse(request, "cache-control").has("no-store") { return false }
+
+    CACHE_AUTHORIZED || !request.contains_key("authorization")
+}
+
+pub fn is_storable(method: &str, status: u16, request: &HashMap<String, String>, response: &HashMap<String, String>) -> bool {
+
+    if !CACHEABLE_METHODS.contains(&method) { return false }
+    if !CACHEABLE_STATUS.contains(&status) && negative_ttl(status).is_none() { return false }
+    if status == 206 || response.contains_key("content-range") { return false }
+    if CacheControl::parse(request, "cache-control").has("no-store") { return false }
+    if response.contains_key("set-cookie") && !CACHE_SET_COOKIE { return false }
+
+    if request.contains_key("authorization") && !CACHE_AUTHORIZED {
+
+        let cache_control = CacheControl::parse(response, "cache-control");
+        return cache_control.has("public") || cache_control.has("s-maxage") || cache_control.has("must-revalidate")
+    }
+
+    true
+}
+
+pub fn can_serve_stale(metadata: &Metadata, directive: &str, default: u64, max: u64) -> bool {
+
+    let cache_control = CacheControl::parse_value(metadata.get_header("cache-control"));
+
+    if cache_control.has("must-revalidate") || cache_control.has("proxy-revalidate") || cache_control.has("no-cache") { return false }
+
+    let window = cache_control.seconds(directive).unwrap_or(default).min(max);
+
+    metadata.get_staleness() <= Duration::from_secs(window)
+}
+
+pub fn freshness_lifetime(headers: &HashMap<String, String>, status: u16, default_ttl: u64) -> Option<Duration> {
+
+    let cache_control = CacheControl::parse(headers, "cache-control");
+
+    if cache_control.has("no-store") || cache_control.has("private") { return None }
+    response_vary_fields(headers)?;
+
+    if headers.get("cache-control").is_none() && headers.get("pragma").map(|p| p.to_lowercase().contains("no-cache")).unwrap_or(false) {
+
+        return Some(Duration::ZERO)
+    }
+
+    if cache_control.has("no-cache") { return Some(Duration::ZERO) }
+
+    let lifetime = explicit_lifetime(headers, &cache_control).unwrap_or_else(|| {
+
+        match negative_ttl(status) {
+
+            Some(ttl) => { Duration::from_secs(ttl) },
+            None => { heuristic_lifetime(headers, default_ttl) },
+        }
+    });
+
+    Some(lifetime.saturating_sub(response_age(headers)))
+}
+
+pub fn negative_ttl(status: u16) -> Option<u64> {
+
+    if !NEGATIVE_CACHE_ENABLED { return None }
+
+    NEGATIVE_CACHE_TTL.iter().find(|(negative, _)| *negative == status).map(|(_, ttl)| *ttl)
+}
+
+fn explicit_lifetime(headers: &HashMap<String, String>, cache_control: &CacheControl) -> Option<Duration> {
+
+    if let Some(secs) = cache_control.seconds("s-maxage") { return Some(Duration::from_secs(secs)) }
+    if let Some(secs) = cache_control.seconds("max-age") { return Some(Duration::from_secs(secs)) }
+
+    let expires = headers.get("expires")?;
+
+    match parse_http_date(expires) {
+
+        Some(expires) => { Some(expires.saturating_sub(response_date(headers))) },
+        None => { Some(Duration::ZERO) },
+    }
+}
+
+fn heuristic_lifetime(headers: &HashMap<String, String>, default_ttl: u64) -> Duration {
+
+    match headers.get("last-modified").and_then(|lm| parse_http_date(lm)) {
+
+        Some(last_modified) => {
+
+            let age = response_date(headers).saturating_sub(last_modified);
+            (age * HEURISTIC_PERCENT / 100).min(Duration::from_secs(HEURISTIC_MAX_TTL))
+        },
+        None => { Duration::from_secs(default_ttl) },
+    }
+}
+
+fn response_date(headers: &HashMap<String, String>) -> Duration {
+
+    match headers.get("date").and_then(|date| parse_http_date(date)) {
+
+        Some(date) => { date },
+        None => { now() },
+    }
+}
+
+fn response_age(headers: &HashMap<String, String>) -> Duration {
+
+    match headers.get("age").and_then(|age| parse_delta_seconds(age)) {
+
+        Some(secs) => { Duration::from_secs(secs) },
+        None => { Duration::ZERO },
+    }
+}
+
+pub fn now() -> Duration {
+
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or(Duration::ZERO)
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::cache::testing::headers;
+
+    #[test]
+    fn delta_seconds_are_clamped() {
+
+        assert_eq!(parse_delta_seconds("60"), Some(60));
+        assert_eq!(parse_delta_seconds("2147483649"), Some(DELTA_SECONDS_MAX));
+        assert_eq!(parse_delta_seconds("18446744073709551615"), Some(DELTA_SECONDS_MAX));
+        assert_eq!(parse_delta_seconds("99999999999999999999999"), Some(DELTA_SECONDS_MAX));
+        assert_eq!(parse_delta_seconds("-1"), None);
+        assert_eq!(parse_delta_seconds(""), None);
+    }
+
+    #[test]
+    fn cache_control_directives() {
+
+        let cache_control = CacheControl::parse(&headers(&[("cache-control", "public, Max-Age=\"30\", no-cache")]), "cache-control");
+
+        assert!(cache_control.has("public"));
+        assert!(cache_control.has("no-cache"));
+        assert_eq!(cache_control.seconds("max-age"), Some(30));
+        assert_eq!(cache_control.seconds("s-maxage"), None);
+    }
+
+    #[test]
+    fn huge_lifetimes_are_clamped() {
+
+        let lifetime = freshness_lifetime(&headers(&[("cache-control", "max-age=18446744073709551615")]), 200, 180);
+        assert_eq!(lifetime, Some(Duration::from_secs(DELTA_SECONDS_MAX)));
+
+        let lifetime = freshness_lifetime(&headers(&[("cache-control", "max-age=60"), ("age", "18446744073709551615")]), 200, 180);
+        assert_eq!(lifetime, Some(Duration::ZERO));
+    }
+
+    #[test]
+    fn explicit_and_negative_lifetimes() {
+
+        assert_eq!(freshness_lifetime(&headers(&[("cache-control", "s-maxage=5, max-age=60")]), 200, 180), Some(Duration::from_secs(5)));
+        assert_eq!(freshness_lifetime(&headers(&[("cache-control", "no-store")]), 200, 180), None);
+        assert_eq!(freshness_lifetime(&headers(&[("vary", "*")]), 200, 180), None);
+        assert_eq!(freshness_lifetime(&headers(&[]), 200, 180), Some(Duration::from_secs(180)));
+        assert_eq!(freshness_lifetime(&headers(&[]), 404, 180), negative_ttl(404).map(Duration::from_secs).or(Some(Duration::from_secs(180))));
+    }
+}
@@ -0,0 +1,218 @@
+use regex::Regex;
+use std::collections::VecDeque;
+use std::sync::{Mutex, MutexGuard};
+
+use super::config::PURGE_LOG_SIZE;
+use super::store::CacheStore;
+use super::vary::primary_key;
+
+#[derive(Clone)]
+pub enum PurgeRule {
+
+    Key(String),
+    Prefix(String),
+    Regex(Regex),
+    Tag(String),
+}
+
+impl PurgeRule {
+
+    pub fn from_query(query: &str) -> Result<Self, String> {
+
+        let (name, value) = match query.split('&').find_map(|param| param.split_once('=')) {
+
+            Some((name, value)) => { (name, percent_decode(value)) },
+            None => { return Err("Missing purge parameter: key, prefix, regex or tag.".to_string()) },
+        };
+
+        if value.is_empty() { return Err(format!("Empty purge parameter {name}.")) }
+
+        match name {
+
+            "key" => { Ok(PurgeRule::Key(value)) },
+            "prefix" => { Ok(PurgeRule::Prefix(value)) },
+            "regex" => {
+
+                match Regex::new(&value) {
+
+                    Ok(x) => { Ok(PurgeRule::Regex(x)) },
+                    Err(e) => { Err(e.to_string()) },
+                }
+            },
+            "tag" => { Ok(PurgeRule::Tag(value)) },
+            _ => { Err(format!("Unknown purge parameter {name}.")) },
+        }
+    }
+
+    pub fn matches(&self, key: &str, tags: &[String]) -> bool {
+
+        let url = key.split_once(' ').map(|(_, url)| url).unwrap_or(key);
+
+        match self {
+
+            PurgeRule::Key(x) => { key == x || url == x },
+            PurgeRule::Prefix(x) if x.starts_with('/') => { url_path(url).starts_with(x.as_str()) },
+            PurgeRule::Prefix(x) => { url.starts_with(x.as_str()) },
+            PurgeRule::Regex(x) => { x.is_match(url) },
+            PurgeRule::Tag(x) => { tags.contains(x) },
+        }
+    }
+}
+
+// Recent rules numbered by generation, so responses fetched before a purge are not stored after it.
+#[derive(Default)]
+pub struct PurgeLog {
+
+    history: Mutex<PurgeHistory>,
+}
+
+#[derive(Default)]
+struct PurgeHistory {
+
+    generation: u64,
+    rules: VecDeque<(u64, PurgeRule)>,
+}
+
+impl PurgeLog {
+
+    pub fn generation(&self) -> u64 {
+
+        self.lock().generation
+    }
+
+    // Whether a rule recorded after `generation` covers the key; rules already forgotten are assumed to.
+    pub fn is_purged(&self, generation: u64, key: &str, tags: &[String]) -> bool {
+
+        let history = self.lock();
+        if history.generation == generation { return false }
+
+        match history.rules.front() {
+
+            Some((oldest, _)) if *oldest > generation + 1 => { true },
+            _ => { history.rules.iter().any(|(x, rule)| *x > generation && rule.matches(key, tags)) },
+        }
+    }
+
+    fn record(&self, rule: &PurgeRule) {
+
+        let mut history = self.lock();
+        history.generation += 1;
+
+        let generation = history.generation;
+        history.rules.push_back((generation, rule.clone()));
+        if history.rules.len() > PURGE_LOG_SIZE { history.rules.pop_front(); }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, PurgeHistory> {
+
+        match self.history.lock() {
+
+            Ok(x) => { x },
+            Err(poisoned) => { poisoned.into_inner() },
+        }
+    }
+}
+
+// The rule is recorded before anything is deleted, so the writer drops older submissions that race with it.
+pub fn purge(store: &dyn CacheStore, log: &PurgeLog, rule: &PurgeRule) -> usize {
+
+    log.record(rule);
+
+    store
+        .entries()
+        .iter()
+        .filter(|entry| rule.matches(primary_key(&entry.key), &entry.tags) && store.delete(&entry.key))
+        .count()
+}
+
+fn url_path(url: &str) -> &str {
+
+    let rest = url.split_once("://").map(|(_, rest)| rest).unwrap_or(url);
+
+    match rest.find('/') {
+
+        Some(index) => { &rest[index..] },
+        None => { "/" },
+    }
+}
+
+fn percent_decode(value: &str) -> String {
+
+    let bytes = value.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut index = 0;
+
+    while index < bytes.len() {
+
+        match bytes[index] {
+
+            b'%' if index + 2 < bytes.len() && bytes[index + 1].is_ascii_hexdigit() && bytes[index + 2].is_ascii_hexdigit() => {
+
+                let hex = String::from_utf8_lossy(&bytes[index + 1..index + 3]).to_string();
+                decoded.push(u8::from_str_radix(&hex, 16).unwrap_or(0));
+                index += 3;
+            },
+            byte => { decoded.push(byte); index += 1; },
+        }
+    }
+
+    String::from_utf8_lossy(&decoded).to_string()
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    const KEY: &str = "GET http://localhost/img/a.png?v=1";
+
+    #[test]
+    fn parses_purge_queries() {
+
+        assert!(matches!(PurgeRule::from_query("key=%2Fa"), Ok(PurgeRule::Key(x)) if x == "/a"));
+        assert!(matches!(PurgeRule::from_query("other&tag=news"), Ok(PurgeRule::Tag(x)) if x == "news"));
+        assert!(PurgeRule::from_query("").is_err());
+        assert!(PurgeRule::from_query("prefix=").is_err());
+        assert!(PurgeRule::from_query("regex=(").is_err());
+        assert!(PurgeRule::from_query("path=/a").is_err());
+    }
+
+    #[test]
+    fn matches_keys_urls_and_tags() {
+
+        let tags = vec!["images".to_string()];
+        let matches = |query: &str| PurgeRule::from_query(query).unwrap().matches(KEY, &tags);
+
+        assert!(matches(&format!("key={KEY}")));
+        assert!(matches("key=http://localhost/img/a.png?v=1"));
+        assert!(!matches("key=http://localhost/img/a.png"));
+        assert!(matches("prefix=/img/"));
+        assert!(matches("prefix=http://localhost/img"));
+        assert!(!matches("prefix=/a"));
+        assert!(matches("regex=%5C.png"));
+        assert!(matches("tag=images"));
+        assert!(!matches("tag=news"));
+    }
+
+    #[test]
+    fn remembers_rules_newer_than_a_generation() {
+
+        let log = PurgeLog::default();
+        let before = log.generation();
+        let rule = PurgeRule::from_query("prefix=/img/").unwrap();
+
+        log.record(&rule);
+        let after = log.generation();
+
+        assert!(log.is_purged(before, KEY, &[]));
+        assert!(!log.is_purged(before, "GET http://localhost/css/a.css", &[]));
+        assert!(!log.is_purged(after, KEY, &[]));
+
+        // Once the rules after a generation are forgotten, everything older counts as purged.
+        for _ in 0..PURGE_LOG_SIZE { log.record(&PurgeRule::Tag("news".to_string())); }
+
+        assert!(log.is_purged(before, "GET http://localhost/css/a.css", &[]));
+        assert!(!log.is_purged(after, "GET http://localhost/css/a.css", &[]));
+        assert!(log.is_purged(after, "GET http://localhost/css/a.css", &["news".to_string()]));
+    }
+}
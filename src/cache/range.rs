@@ -0,0 +1,149 @@
+use std::collections::HashMap;
+
+use super::config::MAX_RANGES;
+use super::httpdate::parse_http_date;
+use super::metadata::Metadata;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum RangeSelection {
+
+    Full,
+    Partial(Vec<(u64, u64)>),
+    Unsatisfiable,
+}
+
+pub fn select_ranges(method: &str, request: &HashMap<String, String>, metadata: &Metadata) -> RangeSelection {
+
+    if method != "GET" || metadata.get_status() != 200 { return RangeSelection::Full }
+
+    let range = match request.get("range") {
+
+        Some(x) => { x },
+        None => { return RangeSelection::Full },
+    };
+
+    if let Some(if_range) = request.get("if-range") {
+
+        if !if_range_matches(if_range, metadata) { return RangeSelection::Full }
+    }
+
+    match parse_range(range, metadata.content_length) {
+
+        Some(ranges) if ranges.is_empty() => { RangeSelection::Unsatisfiable },
+        Some(ranges) => { RangeSelection::Partial(ranges) },
+        None => { RangeSelection::Full },
+    }
+}
+
+fn if_range_matches(if_range: &str, metadata: &Metadata) -> bool {
+
+    let if_range = if_range.trim();
+
+    if if_range.starts_with('"') || if_range.starts_with("W/") {
+
+        return !if_range.starts_with("W/") && metadata.get_header("etag").is_some_and(|etag| etag.trim() == if_range)
+    }
+
+    match (parse_http_date(if_range), metadata.get_header("last-modified").and_then(|lm| parse_http_date(lm))) {
+
+        (Some(date), Some(last_modified)) => { date == last_modified },
+        _ => { false },
+    }
+}
+
+fn parse_range(range: &str, length: u64) -> Option<Vec<(u64, u64)>> {
+
+    let specs = range.trim().strip_prefix("bytes=")?;
+    let mut ranges = Vec::new();
+    let mut count = 0;
+
+    for spec in specs.split(',').map(|spec| spec.trim()).filter(|spec| !spec.is_empty()) {
+
+        count += 1;
+        if count > MAX_RANGES { return None }
+
+        let (first, last) = spec.split_once('-')?;
+
+        let range = if first.is_empty() {
+
+            let suffix: u64 = last.parse().ok()?;
+            if suffix == 0 || length == 0 { continue }
+
+            (length.saturating_sub(suffix), length - 1)
+        } else {
+
+            let first: u64 = first.parse().ok()?;
+            let last: u64 = if last.is_empty() { u64::MAX } else { last.parse().ok()? };
+
+            if last < first { return None }
+            if first >= length { continue }
+
+            (first, last.min(length - 1))
+        };
+
+        ranges.push(range);
+    }
+
+    if count == 0 { return None }
+
+    Some(ranges)
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::cache::testing::{headers, metadata};
+
+    #[test]
+    fn parses_byte_ranges() {
+
+        assert_eq!(parse_range("bytes=0-9", 100), Some(vec![(0, 9)]));
+        assert_eq!(parse_range("bytes=90-", 100), Some(vec![(90, 99)]));
+        assert_eq!(parse_range("bytes=-10", 100), Some(vec![(90, 99)]));
+        assert_eq!(parse_range("bytes=-500", 100), Some(vec![(0, 99)]));
+        assert_eq!(parse_range("bytes=50-500", 100), Some(vec![(50, 99)]));
+        assert_eq!(parse_range("bytes=0-0, 10-19", 100), Some(vec![(0, 0), (10, 19)]));
+    }
+
+    #[test]
+    fn rejects_malformed_ranges() {
+
+        assert_eq!(parse_range("items=0-9", 100), None);
+        assert_eq!(parse_range("bytes=9-0", 100), None);
+        assert_eq!(parse_range("bytes=a-b", 100), None);
+        assert_eq!(parse_range("bytes=", 100), None);
+        assert_eq!(parse_range(&format!("bytes={}", vec!["0-0"; MAX_RANGES + 1].join(",")), 100), None);
+    }
+
+    #[test]
+    fn unsatisfiable_ranges_are_empty() {
+
+        assert_eq!(parse_range("bytes=100-", 100), Some(vec![]));
+        assert_eq!(parse_range("bytes=-0", 100), Some(vec![]));
+        assert_eq!(select_ranges("GET", &headers(&[("range", "bytes=200-")]), &metadata(200, 100, &[])), RangeSelection::Unsatisfiable);
+    }
+
+    #[test]
+    fn selects_ranges_for_get_only() {
+
+        let request = headers(&[("range", "bytes=0-9")]);
+
+        assert_eq!(select_ranges("GET", &request, &metadata(200, 100, &[])), RangeSelection::Partial(vec![(0, 9)]));
+        assert_eq!(select_ranges("HEAD", &request, &metadata(200, 100, &[])), RangeSelection::Full);
+        assert_eq!(select_ranges("GET", &headers(&[]), &metadata(200, 100, &[])), RangeSelection::Full);
+    }
+
+    #[test]
+    fn if_range_needs_a_strong_match() {
+
+        let metadata = metadata(200, 100, &[("etag", "\"v1\""), ("last-modified", "Sun, 06 Nov 1994 08:49:37 GMT")]);
+        let range = |if_range: &str| select_ranges("GET", &headers(&[("range", "bytes=0-9"), ("if-range", if_range)]), &metadata);
+
+        assert_eq!(range("\"v1\""), RangeSelection::Partial(vec![(0, 9)]));
+        assert_eq!(range("\"v2\""), RangeSelection::Full);
+        assert_eq!(range("W/\"v1\""), RangeSelection::Full);
+        assert_eq!(range("Sun, 06 Nov 1994 08:49:37 GMT"), RangeSelection::Partial(vec![(0, 9)]));
+        assert_eq!(range("Mon, 07 Nov 1994 08:49:37 GMT"), RangeSelection::Full);
+    }
+}
@@ -0,0 +1,162 @@
+use std::time::Duration;
+
+use super::disk::DiskStore;
+use super::filedata::FileData;
+use super::memory::MemoryStore;
+use super::metadata::Metadata;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CacheBackend {
+
+    // One file per entry under the cache folder.
+    Disk,
+    // Memory only, so nothing survives a restart.
+    Memory,
+    // A memory tier holding copies of the hottest disk entries.
+    Tiered,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct StoreStats {
+
+    pub entries: usize,
+    pub bytes: u64,
+}
+
+#[derive(Debug, Clone)]
+pub struct StoreEntry {
+
+    pub key: String,
+    pub tags: Vec<String>,
+    pub size: u64,
+    pub expires: Duration,
+}
+
+// Entries are identified by their variant key; how they are laid out is up to each backend.
+pub trait CacheStore: Send + Sync {
+
+    fn metadata(&self, key: &str) -> Result<Metadata, String>;
+
+    fn read(&self, key: &str, metadata: Metadata) -> Result<FileData, String>;
+
+    fn write(&self, filedata: &FileData) -> bool;
+
+    fn refresh(&self, key: &str, metadata: &mut Metadata, ttl: Duration) -> Result<(), String>;
+
+    fn delete(&self, key: &str) -> bool;
+
+    fn vary_fields(&self, key: &str) -> Vec<String>;
+
+    fn entries(&self) -> Vec<StoreEntry>;
+
+    fn stats(&self) -> StoreStats;
+
+    // Removes the entries that expired before the cutoff and returns what was reclaimed.
+    fn remove_expired(&self, cutoff: Duration) -> StoreStats;
+
+    // Looks for entries the store lost track of, for backends that can have them.
+    fn scan(&self, _cutoff: Duration) -> StoreStats { StoreStats::default() }
+
+    // Persists whatever the backend needs to survive a restart.
+    fn flush(&self) {}
+}
+
+pub struct TieredStore {
+
+    memory: MemoryStore,
+    disk: DiskStore,
+}
+
+impl TieredStore {
+
+    pub fn new(memory: MemoryStore, disk: DiskStore) -> Self {
+
+        TieredStore { memory, disk }
+    }
+}
+
+// The memory tier only holds copies of disk entries, so a copy whose disk entry is gone is dropped.
+impl CacheStore for TieredStore {
+
+    fn metadata(&self, key: &str) -> Result<Metadata, String> {
+
+        if !self.disk.contains(key) { self.memory.delete(key); }
+        else if let Ok(metadata) = self.memory.metadata(key) { return Ok(metadata) }
+
+        self.disk.metadata(key)
+    }
+
+    fn read(&self, key: &str, metadata: Metadata) -> Result<FileData, String> {
+
+        if self.disk.touch(key) {
+
+            if let Ok(filedata) = self.memory.read(key, metadata.clone()) { return Ok(filedata) }
+        }
+        else { self.memory.delete(key); }
+
+        let filedata = self.disk.read(key, metadata)?;
+        self.memory.write(&filedata);
+
+        Ok(filedata)
+    }
+
+    fn write(&self, filedata: &FileData) -> bool {
+
+        let evicted = match self.disk.write_evicting(filedata) {
+
+            Some(x) => { x },
+            None => { return false },
+        };
+
+        for key in &evicted { self.memory.delete(key); }
+
+        self.memory.write(filedata);
+        true
+    }
+
+    fn refresh(&self, key: &str, metadata: &mut Metadata, ttl: Duration) -> Result<(), String> {
+
+        self.disk.refresh(key, metadata, ttl)?;
+
+        if self.memory.refresh(key, &mut metadata.clone(), ttl).is_err() { self.memory.delete(key); }
+
+        Ok(())
+    }
+
+    fn delete(&self, key: &str) -> bool {
+
+        let in_memory = self.memory.delete(key);
+        self.disk.delete(key) || in_memory
+    }
+
+    fn vary_fields(&self, key: &str) -> Vec<String> {
+
+        self.disk.vary_fields(key)
+    }
+
+    fn entries(&self) -> Vec<StoreEntry> {
+
+        self.disk.entries()
+    }
+
+    fn stats(&self) -> StoreStats {
+
+        self.disk.stats()
+    }
+
+    fn remove_expired(&self, cutoff: Duration) -> StoreStats {
+
+        self.memory.remove_expired(cutoff);
+        self.disk.remove_expired(cutoff)
+    }
+
+    fn scan(&self, cutoff: Duration) -> StoreStats {
+
+        self.disk.scan(cutoff)
+    }
+
+    fn flush(&self) {
+
+        self.disk.flush();
+    }
+}
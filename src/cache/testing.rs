@@ -0,0 +1,27 @@
+use std::collections::HashMap;
+use std::path::PathBuf;
+use uuid::Uuid;
+
+use super::filedata::FileData;
+use super::metadata::Metadata;
+
+pub fn headers(pairs: &[(&str, &str)]) -> HashMap<String, String> {
+
+    pairs.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect()
+}
+
+pub fn metadata(status: u16, content_length: u64, response: &[(&str, &str)]) -> Metadata {
+
+    Metadata::default(60, content_length, status, &headers(response)).unwrap()
+}
+
+pub fn filedata(key: &str, body: &[u8], response: &[(&str, &str)]) -> FileData {
+
+    FileData::default(60, body.len() as u64, key, body.to_vec(), 200, &headers(response)).unwrap()
+}
+
+// A unique path under the system temp folder; tests remove whatever they create there.
+pub fn temp_path() -> PathBuf {
+
+    std::env::temp_dir().join(format!("rplb-test-{}", Uuid::new_v4().simple()))
+}
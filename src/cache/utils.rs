@@ -1,45 +1,129 @@
-use log::{error, info};
-use std::path::PathBuf;
-use std::{fs, time};
-use std::sync::mpsc::Receiver;
+use log::info;
+use std::collections::HashMap;
+use std::time::Duration;
+use std::time;
+use std::sync::Arc;
 use std::thread;
+use std::time::Instant;
 
-use super::filedata::delete_file;
+use super::config::{CLEANER_FULL_SCAN_INTERVAL, CLEANER_GRACE_PERIOD};
 use super::metadata::Metadata;
+use super::policy::freshness_lifetime;
+use super::store::{CacheStore, StoreStats};
+use super::writer::WriterStats;
 use crate::cache::filedata::FileData;
 
-pub fn run_writer(receiver: Receiver<FileData>) {
-    thread::spawn(move || loop {
+pub enum CacheLookup {
 
-        match receiver.recv() {
+    Fresh(FileData),
+    Stale(Metadata),
+    Miss,
+}
 
-            Ok(filedata) => {
-                if filedata.get_path().as_path().is_file() { info!("File already exists. Not writing"); }
-                else if !FileData::write_file(&filedata) { info!("Failed to write FileData."); }
-            },
-            Err(_) => { error!("Failed to receive cache file"); },
-        }
-    });
+pub fn lookup_cache_entry(store: &dyn CacheStore, key: &str) -> CacheLookup {
+
+    let metadata = match store.metadata(key) {
+
+        Ok(x) => { x },
+        Err(_) => { return CacheLookup::Miss },
+    };
+
+    if metadata.ttl_check() { return CacheLookup::Stale(metadata) }
+
+    match read_cache_body(store, key, metadata) {
+
+        Some(x) => { CacheLookup::Fresh(x) },
+        None => { CacheLookup::Miss },
+    }
 }
 
-static SLEEP_TIME: u64 = 30;
+pub fn read_cache_body(store: &dyn CacheStore, key: &str, metadata: Metadata) -> Option<FileData> {
 
-pub fn run_cleaner(cache_dir: PathBuf) {
-    thread::spawn(move || loop {
-        clean_folders(cache_dir.clone());
-        thread::sleep(time::Duration::from_secs(SLEEP_TIME));
-    });
+    match store.read(key, metadata) {
+
+        Ok(x) => { Some(x) },
+        Err(e) => {
+
+            info!("Discarding corrupt cache entry {key:?}: {e}");
+            store.delete(key);
+            None
+        },
+    }
+}
+
+pub fn refresh_stale_entry(store: &dyn CacheStore, key: &str, mut metadata: Metadata, headers: &HashMap<String, String>, ttl: u64) -> Option<FileData> {
+
+    let mut merged: HashMap<String, String> = metadata.get_headers().iter().cloned().collect();
+    merged.extend(headers.iter().map(|(k, v)| (k.clone(), v.clone())));
+
+    let lifetime = freshness_lifetime(&merged, metadata.get_status(), ttl).unwrap_or(Duration::ZERO);
+
+    if let Err(e) = store.refresh(key, &mut metadata, lifetime) {
+
+        info!("Failed to refresh cache entry {key:?}: {e}");
+        return None
+    }
+
+    read_cache_body(store, key, metadata)
+}
+
+pub fn is_fresh(store: &dyn CacheStore, key: &str) -> bool {
+
+    match store.metadata(key) {
+
+        Ok(metadata) => { !metadata.ttl_check() },
+        Err(_) => { false },
+    }
 }
 
-fn clean_folders(path: PathBuf) {
-    if let Ok(entry) = fs::read_dir(path.as_path()) {
-        for dir_entry in entry.flatten() {
-            let dir_entry_path = dir_entry.path();
-            if dir_entry_path.is_file() {
-                if let Ok(metadata) = Metadata::parse_file(&dir_entry_path) {
-                    if metadata.ttl_check() { delete_file(dir_entry_path); }
-                }
+static SLEEP_TIME: u64 = 30;
+
+pub fn run_cleaner(store: Arc<dyn CacheStore>, writer: Arc<WriterStats>) {
+    thread::spawn(move || {
+
+        // The first pass always scans, so files written after the last index flush before a crash are indexed.
+        let mut last_scan: Option<Instant> = None;
+        let mut last_writes = (0, 0, 0);
+
+        loop {
+
+            let cutoff = cleaner_cutoff();
+            let mut reclaimed = StoreStats::default();
+
+            if last_scan.is_none_or(|scan| scan.elapsed() >= Duration::from_secs(CLEANER_FULL_SCAN_INTERVAL)) {
+
+                reclaimed = store.scan(cutoff);
+                last_scan = Some(Instant::now());
+            }
+
+            let expired = store.remove_expired(cutoff);
+            reclaimed.entries += expired.entries;
+            reclaimed.bytes += expired.bytes;
+
+            if reclaimed.entries > 0 { println!("Cache cleaner reclaimed {} entries ({} bytes)", reclaimed.entries, reclaimed.bytes); }
+
+            let writes = (writer.queued(), writer.dropped(), writer.failed());
+
+            if writes != last_writes {
+
+                let stats = store.stats();
+                println!("Cache store: {} entries ({} bytes)", stats.entries, stats.bytes);
+                println!("Cache writer: {} queued, {} written, {} dropped, {} failed", writer.queued(), writer.written(), writer.dropped(), writer.failed());
+                last_writes = writes;
             }
+
+            store.flush();
+
+            thread::sleep(time::Duration::from_secs(SLEEP_TIME));
         }
+    });
+}
+
+fn cleaner_cutoff() -> Duration {
+
+    match time::SystemTime::now().duration_since(time::UNIX_EPOCH) {
+
+        Ok(x) => { x.saturating_sub(Duration::from_secs(CLEANER_GRACE_PERIOD)) },
+        Err(_) => { Duration::ZERO },
     }
-}
\ No newline at end of file
+}
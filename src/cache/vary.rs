@@ -0,0 +1,85 @@
+use std::collections::HashMap;
+
+// Accept-Encoding is stripped before forwarding, so the origin always answers identity.
+const IGNORED_VARY_FIELDS: [&str; 1] = ["accept-encoding"];
+
+pub fn vary_fields(vary: &str) -> Option<Vec<String>> {
+
+    let mut fields: Vec<String> = vary
+        .split(',')
+        .map(|field| field.trim().to_lowercase())
+        .filter(|field| !field.is_empty() && !IGNORED_VARY_FIELDS.contains(&field.as_str()))
+        .collect();
+
+    if fields.iter().any(|field| field == "*") { return None }
+
+    fields.sort();
+    fields.dedup();
+
+    Some(fields)
+}
+
+pub fn response_vary_fields(headers: &HashMap<String, String>) -> Option<Vec<String>> {
+
+    match headers.get("vary") {
+
+        Some(vary) => { vary_fields(vary) },
+        None => { Some(Vec::new()) },
+    }
+}
+
+pub fn variant_key(key: &str, fields: &[String], request: &HashMap<String, String>) -> String {
+
+    let mut variant = key.to_string();
+
+    for field in fields {
+
+        let value = request.get(field).map(|value| normalize_value(value)).unwrap_or_default();
+        variant.push_str(&format!("\n{field}: {value}"));
+    }
+
+    variant
+}
+
+// A variant key is the primary key followed by one line per vary field.
+pub fn primary_key(variant: &str) -> &str {
+
+    variant.split('\n').next().unwrap_or(variant)
+}
+
+fn normalize_value(value: &str) -> String {
+
+    value
+        .split(',')
+        .map(|item| item.split_whitespace().collect::<Vec<&str>>().join(" "))
+        .collect::<Vec<String>>()
+        .join(",")
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::cache::testing::headers;
+
+    #[test]
+    fn vary_fields_are_normalized() {
+
+        assert_eq!(vary_fields("User-Agent, accept-language, Accept-Encoding, user-agent"), Some(vec!["accept-language".to_string(), "user-agent".to_string()]));
+        assert_eq!(vary_fields(" , "), Some(vec![]));
+        assert_eq!(vary_fields("Accept, *"), None);
+        assert_eq!(response_vary_fields(&headers(&[])), Some(vec![]));
+    }
+
+    #[test]
+    fn variant_keys_split_back_into_the_primary_key() {
+
+        let fields = vec!["accept-language".to_string(), "x-missing".to_string()];
+        let variant = variant_key("GET http://localhost/a", &fields, &headers(&[("accept-language", "en,  es")]));
+
+        assert_eq!(variant, "GET http://localhost/a\naccept-language: en,es\nx-missing: ");
+        assert_eq!(primary_key(&variant), "GET http://localhost/a");
+        assert_eq!(variant_key("GET http://localhost/a", &[], &headers(&[])), "GET http://localhost/a");
+        assert_eq!(primary_key("GET http://localhost/a"), "GET http://localhost/a");
+    }
+}
@@ -0,0 +1,205 @@
+use log::{error, info};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{self, Receiver, SyncSender, TrySendError};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use super::filedata::FileData;
+use super::purge::PurgeLog;
+use super::store::CacheStore;
+use super::utils::is_fresh;
+use super::vary::primary_key;
+
+#[derive(Default)]
+pub struct WriterStats {
+
+    queued: AtomicU64,
+    written: AtomicU64,
+    dropped: AtomicU64,
+    failed: AtomicU64,
+}
+
+impl WriterStats {
+
+    pub fn queued(&self) -> u64 { self.queued.load(Ordering::Relaxed) }
+    pub fn written(&self) -> u64 { self.written.load(Ordering::Relaxed) }
+    pub fn dropped(&self) -> u64 { self.dropped.load(Ordering::Relaxed) }
+    pub fn failed(&self) -> u64 { self.failed.load(Ordering::Relaxed) }
+}
+
+// A response waiting to be written, with the purge generation from before it was fetched.
+// An overwrite replaces the entry even if it is still fresh.
+struct Submission {
+
+    filedata: FileData,
+    generation: u64,
+    overwrite: bool,
+}
+
+#[derive(Clone)]
+pub struct CacheWriter {
+
+    sender: SyncSender<Submission>,
+    stats: Arc<WriterStats>,
+    block_when_full: bool,
+}
+
+impl CacheWriter {
+
+    pub fn submit(&self, filedata: FileData, generation: u64, overwrite: bool) -> bool {
+
+        let submission = Submission { filedata, generation, overwrite };
+
+        let sent = if self.block_when_full { self.sender.send(submission).is_ok() } else {
+
+            match self.sender.try_send(submission) {
+
+                Ok(_) => { true },
+                Err(TrySendError::Full(submission)) => {
+
+                    self.stats.dropped.fetch_add(1, Ordering::Relaxed);
+                    info!("Cache writer queue full. Not caching {:?}", submission.filedata.metadata.key);
+                    return false
+                },
+                Err(TrySendError::Disconnected(_)) => { false },
+            }
+        };
+
+        if sent { self.stats.queued.fetch_add(1, Ordering::Relaxed); }
+        else { self.stats.failed.fetch_add(1, Ordering::Relaxed); }
+
+        sent
+    }
+
+    pub fn stats(&self) -> &Arc<WriterStats> {
+
+        &self.stats
+    }
+}
+
+pub fn run_writers(store: Arc<dyn CacheStore>, purges: Arc<PurgeLog>, queue_size: usize, threads: usize, block_when_full: bool) -> CacheWriter {
+
+    let (sender, receiver) = mpsc::sync_channel(queue_size);
+    let receiver = Arc::new(Mutex::new(receiver));
+    let stats = Arc::new(WriterStats::default());
+
+    for _ in 0..threads.max(1) {
+
+        let receiver = Arc::clone(&receiver);
+        let store = Arc::clone(&store);
+        let purges = Arc::clone(&purges);
+        let stats = Arc::clone(&stats);
+
+        thread::spawn(move || write_loop(&receiver, store.as_ref(), &purges, &stats));
+    }
+
+    CacheWriter { sender, stats, block_when_full }
+}
+
+fn write_loop(receiver: &Mutex<Receiver<Submission>>, store: &dyn CacheStore, purges: &PurgeLog, stats: &WriterStats) {
+
+    loop {
+
+        let received = match receiver.lock() {
+
+            Ok(x) => { x.recv() },
+            Err(_) => { error!("Cache writer queue lock poisoned"); return },
+        };
+
+        let Submission { filedata, generation, overwrite } = match received {
+
+            Ok(x) => { x },
+            Err(_) => { return },
+        };
+
+        let key = &filedata.metadata.key;
+        let tags = filedata.metadata.get_surrogate_keys();
+        let is_purged = || purges.is_purged(generation, primary_key(key), &tags);
+
+        if is_purged() { info!("Purged while queued. Not writing {key:?}"); }
+        else if !overwrite && is_fresh(store, key) { info!("File already exists. Not writing"); }
+        else if store.write(&filedata) {
+
+            // A purge that started during the write may have listed the entries before this one existed.
+            if is_purged() { store.delete(key); }
+            else { stats.written.fetch_add(1, Ordering::Relaxed); }
+        }
+        else {
+
+            stats.failed.fetch_add(1, Ordering::Relaxed);
+            info!("Failed to write FileData.");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use std::time::{Duration, Instant};
+
+    use crate::cache::memory::MemoryStore;
+    use crate::cache::purge::{purge, PurgeRule};
+    use crate::cache::testing::filedata;
+
+    const KEY: &str = "GET http://localhost/a";
+
+    fn wait_for(done: impl Fn() -> bool) {
+
+        let start = Instant::now();
+        while !done() && start.elapsed() < Duration::from_secs(5) { thread::sleep(Duration::from_millis(5)); }
+    }
+
+    #[test]
+    fn counts_drops_when_the_queue_is_full() {
+
+        let (sender, receiver) = mpsc::sync_channel(1);
+        let writer = CacheWriter { sender, stats: Arc::new(WriterStats::default()), block_when_full: false };
+
+        assert!(writer.submit(filedata(KEY, b"a", &[]), 0, false));
+        assert!(!writer.submit(filedata(KEY, b"b", &[]), 0, false));
+        assert_eq!((writer.stats().queued(), writer.stats().dropped()), (1, 1));
+
+        drop(receiver);
+        assert!(!writer.submit(filedata(KEY, b"c", &[]), 0, false));
+        assert_eq!(writer.stats().failed(), 1);
+    }
+
+    #[test]
+    fn workers_write_every_queued_response() {
+
+        let store: Arc<dyn CacheStore> = Arc::new(MemoryStore::new(1 << 20, 1 << 20));
+        let writer = run_writers(Arc::clone(&store), Arc::new(PurgeLog::default()), 8, 2, true);
+        let keys: Vec<String> = (0..10).map(|x| format!("{KEY}{x}")).collect();
+
+        for key in &keys { assert!(writer.submit(filedata(key, b"body", &[]), 0, false)); }
+        wait_for(|| writer.stats().written() == 10);
+
+        assert_eq!(writer.stats().written(), 10);
+        assert!(keys.iter().all(|key| store.metadata(key).is_ok()));
+    }
+
+    #[test]
+    fn skips_fresh_and_purged_entries_unless_overwriting() {
+
+        let store: Arc<dyn CacheStore> = Arc::new(MemoryStore::new(1 << 20, 1 << 20));
+        let purges = Arc::new(PurgeLog::default());
+        let writer = run_writers(Arc::clone(&store), Arc::clone(&purges), 8, 1, true);
+        let body = |key: &str| store.read(key, store.metadata(key).unwrap()).unwrap().get_content().map(<[u8]>::to_vec);
+
+        writer.submit(filedata(KEY, b"old", &[]), 0, false);
+        writer.submit(filedata(KEY, b"new", &[]), 0, false);
+        writer.submit(filedata(KEY, b"forced", &[]), 0, true);
+        wait_for(|| writer.stats().written() == 2);
+        assert_eq!(body(KEY), Some(b"forced".to_vec()));
+
+        // A response fetched before a purge that covers it is dropped; one fetched after is kept.
+        let before = purges.generation();
+        purge(store.as_ref(), &purges, &PurgeRule::Key(KEY.to_string()));
+
+        writer.submit(filedata(KEY, b"stale", &[]), before, true);
+        writer.submit(filedata(KEY, b"fresh", &[]), purges.generation(), true);
+        wait_for(|| writer.stats().written() == 3);
+        assert_eq!(body(KEY), Some(b"fresh".to_vec()));
+    }
+}
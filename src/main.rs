@@ -1,12 +1,22 @@
 use std::net::TcpListener;
 use std::path::Path;
-use std::sync::mpsc;
+use std::sync::Arc;
 
 use reverse_proxy_lb::proxy::config::{IP_LISTENER, NUM_THREADS};
-use reverse_proxy_lb::proxy::connecting::handle_connection;
-use reverse_proxy_lb::proxy::threadpool::{read_ip_server, ThreadPool};
-use reverse_proxy_lb::cache::utils::run_writer;
+use reverse_proxy_lb::proxy::connecting::{handle_connection, CacheContext};
+use reverse_proxy_lb::proxy::error_response::ErrorPages;
+use reverse_proxy_lb::proxy::threadpool::{read_ip_server, BackgroundPool, ThreadPool};
+use reverse_proxy_lb::cache::config::{
+    CACHE_BACKEND, DISK_CACHE_ENTRIES, DISK_CACHE_SIZE, MEMORY_CACHE_SIZE, MEMORY_MAX_OBJECT_SIZE, REVALIDATION_QUEUE_SIZE, REVALIDATION_THREADS,
+    WRITER_BLOCK_WHEN_FULL, WRITER_QUEUE_SIZE, WRITER_THREADS,
+};
+use reverse_proxy_lb::cache::disk::DiskStore;
+use reverse_proxy_lb::cache::inflight::InFlight;
+use reverse_proxy_lb::cache::memory::MemoryStore;
+use reverse_proxy_lb::cache::purge::PurgeLog;
+use reverse_proxy_lb::cache::store::{CacheBackend, CacheStore, TieredStore};
 use reverse_proxy_lb::cache::utils::run_cleaner;
+use reverse_proxy_lb::cache::writer::run_writers;
 
 fn main() {
     match TcpListener::bind(IP_LISTENER) {
@@ -14,15 +24,34 @@ fn main() {
             println!("Listening in {}", IP_LISTENER);
             let pool = ThreadPool::new(NUM_THREADS);
             let (push, pop) = read_ip_server();
-            let (sender, receiver) = mpsc::channel();
+            let error_pages = Arc::new(ErrorPages::load());
 
             let path = String::from(r"./cachefiles");
-            let ttl = 180 as u64;
+            let ttl: u64 = 180;
             let cache_dir = Path::new(path.as_str());
-            run_cleaner(<&std::path::Path>::clone(&cache_dir).to_path_buf());
-            run_writer(receiver);
+            let store: Arc<dyn CacheStore> = match CACHE_BACKEND {
+                CacheBackend::Memory => Arc::new(MemoryStore::new(MEMORY_CACHE_SIZE, MEMORY_MAX_OBJECT_SIZE)),
+                CacheBackend::Disk => Arc::new(DiskStore::open(cache_dir, DISK_CACHE_SIZE, DISK_CACHE_ENTRIES)),
+                CacheBackend::Tiered => {
+                    let disk = DiskStore::open(cache_dir, DISK_CACHE_SIZE, DISK_CACHE_ENTRIES);
+                    Arc::new(TieredStore::new(MemoryStore::new(MEMORY_CACHE_SIZE, MEMORY_MAX_OBJECT_SIZE), disk))
+                }
+            };
+            let purges = Arc::new(PurgeLog::default());
+            let writer = run_writers(Arc::clone(&store), Arc::clone(&purges), WRITER_QUEUE_SIZE, WRITER_THREADS, WRITER_BLOCK_WHEN_FULL);
+            run_cleaner(Arc::clone(&store), Arc::clone(writer.stats()));
 
-            handle_connection(pool, listener, &push, &pop, &sender, cache_dir.to_path_buf(), ttl, true);
+            let cache = CacheContext {
+                writer,
+                ttl,
+                is_available: true,
+                inflight: Arc::new(InFlight::default()),
+                store,
+                purges,
+                revalidator: BackgroundPool::new(REVALIDATION_THREADS, REVALIDATION_QUEUE_SIZE),
+            };
+
+            handle_connection(pool, listener, &push, &pop, &cache, error_pages);
         }
         Err(_) => println!("Failed to listen in {}", IP_LISTENER),
     }
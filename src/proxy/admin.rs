@@ -0,0 +1,71 @@
+use std::collections::HashMap;
+use std::net::TcpStream;
+
+use crate::cache::key::CacheKey;
+use crate::cache::purge::{purge, PurgeRule};
+use crate::proxy::config::{PURGE_ALLOWED_IPS, PURGE_ENDPOINT};
+use crate::proxy::connecting::CacheContext;
+use crate::proxy::error_response::ErrorPages;
+use crate::proxy::responser::write_response;
+
+pub fn is_purge_request(method: &str, target: &str) -> bool {
+    method == "PURGE" || target.split('?').next() == Some(PURGE_ENDPOINT)
+}
+
+pub fn is_trusted_client(st_client: &TcpStream, allowed: &[&str]) -> bool {
+    match st_client.peer_addr() {
+        Ok(addr) => allowed.contains(&addr.ip().to_string().as_str()),
+        Err(_) => false,
+    }
+}
+
+pub fn handle_purge(
+    st_client: &mut TcpStream,
+    method: &str,
+    target: &str,
+    header: &HashMap<String, String>,
+    cache: &CacheContext,
+    error_pages: &ErrorPages,
+) {
+    if !is_trusted_client(st_client, &PURGE_ALLOWED_IPS) {
+        error_pages.write(st_client, 403);
+        return;
+    }
+
+    let (path, query) = target.split_once('?').unwrap_or((target, ""));
+
+    let rule = if path == PURGE_ENDPOINT {
+        if method != "POST" && method != "PURGE" {
+            error_pages.write(st_client, 405);
+            return;
+        }
+        PurgeRule::from_query(query)
+    } else {
+        CacheKey::new("GET", target, header).map(|key| PurgeRule::Key(key.to_string()))
+    };
+
+    let rule = match rule {
+        Ok(rule) => rule,
+        Err(e) => {
+            println!("Rejected purge request: {}", e);
+            error_pages.write(st_client, 400);
+            return;
+        }
+    };
+
+    let purged = purge(cache.store.as_ref(), &cache.purges, &rule);
+
+    if path != PURGE_ENDPOINT && purged == 0 {
+        error_pages.write(st_client, 404);
+        return;
+    }
+
+    let body = format!("{{\"purged\": {}}}\n", purged).into_bytes();
+    let mut headers = HashMap::from([
+        ("content-type".to_string(), "application/json".to_string()),
+        ("content-length".to_string(), body.len().to_string()),
+        ("connection".to_string(), "close".to_string()),
+    ]);
+
+    write_response(&mut "HTTP/1.1 200 OK\r\n".to_string(), &mut headers, st_client, body);
+}
@@ -2,3 +2,12 @@ pub const IP_SERVERS: [&str; 3] = ["127.0.0.1:3000", "127.0.0.1:3000", "127.0.0.
 pub const IP_LISTENER: &str = "0.0.0.0:8080";
 pub const NUM_THREADS: usize = 8;
 pub const DIR_LOG: &str = "log.txt";
+pub const MAX_BODY_SIZE: usize = 10 * 1024 * 1024;
+pub const MAX_RESPONSE_SIZE: usize = 64 * 1024 * 1024;
+pub const UPSTREAM_TIMEOUT: u64 = 30;
+pub const ERROR_PAGES: [(u16, &str); 3] = [(502, "failed.html"), (503, "failed.html"), (504, "failed.html")];
+pub const PURGE_ALLOWED_IPS: [&str; 2] = ["127.0.0.1", "::1"];
+pub const PURGE_ENDPOINT: &str = "/_cache/purge";
+pub const INTERNAL_RESPONSE_HEADERS: [&str; 1] = ["surrogate-key"];
+pub const CACHE_BYPASS_ALLOWED_IPS: [&str; 2] = ["127.0.0.1", "::1"];
+pub const CACHE_KEY_HEADER: bool = false;
@@ -1,26 +1,41 @@
 use std::collections::HashMap;
 use std::io::{Error, ErrorKind};
 use std::net::{TcpListener, TcpStream};
-use std::sync::mpsc::Sender;
 use std::sync::{
     mpsc::{Receiver, SyncSender},
     Arc, Mutex,
 };
-use std::time;
-use std::path::{PathBuf, Path};
+use std::time::{self, Duration};
 
-use crate::proxy::request::{read_request, write_request, is_cache_request};
-use crate::proxy::responser::{read_response, write_error, write_response, write_failed_to_connect, write_resp_err_log};
-use crate::proxy::threadpool::ThreadPool;
+use crate::proxy::admin::{handle_purge, is_purge_request, is_trusted_client};
+use crate::proxy::config::{CACHE_BYPASS_ALLOWED_IPS, CACHE_KEY_HEADER, UPSTREAM_TIMEOUT};
+use crate::proxy::error_response::{reason_phrase, ErrorPages};
+use crate::proxy::request::{read_request, write_request, is_cache_request, parse_cache_info, HttpMessage};
+use crate::proxy::responser::{parse_status, read_response, strip_internal_headers, write_not_modified, write_response, write_resp_err_log};
+use crate::proxy::threadpool::{BackgroundPool, ThreadPool};
 use crate::cache::metadata::Metadata;
-use super::responser::write_response_from_file;
-use crate::cache::filedata::{create_file_path, FileData};
+use crate::cache::utils::{lookup_cache_entry, read_cache_body, refresh_stale_entry, CacheLookup};
+use super::responser::{write_partial_from_file, write_range_not_satisfiable, write_response_from_file};
+use crate::cache::filedata::FileData;
+use crate::cache::conditional::{evaluate_preconditions, Precondition};
+use crate::cache::inflight::{Flight, InFlight};
+use crate::cache::key::CacheKey;
+use crate::cache::range::{select_ranges, RangeSelection};
+use crate::cache::purge::PurgeLog;
+use crate::cache::store::CacheStore;
+use crate::cache::vary::{primary_key, response_vary_fields, variant_key};
+use crate::cache::writer::CacheWriter;
+use crate::cache::config::{CACHE_MAX_OBJECT_SIZE, COALESCE_TIMEOUT, STALE_IF_ERROR_DEFAULT, STALE_IF_ERROR_MAX, STALE_WHILE_REVALIDATE_DEFAULT, STALE_WHILE_REVALIDATE_MAX};
+use crate::cache::policy::{can_serve_stale, freshness_lifetime, is_storable, may_store_request, CacheControl};
 
 fn connect_to_server(ip: &str, retries: u16) -> Result<TcpStream, std::io::Error> {
-    if let Ok(st_server) = TcpStream::connect(&ip) {
+    if let Ok(st_server) = TcpStream::connect(ip) {
+        let timeout = Some(time::Duration::from_secs(UPSTREAM_TIMEOUT));
+        st_server.set_read_timeout(timeout)?;
+        st_server.set_write_timeout(timeout)?;
         Ok(st_server)
     } else if retries < 1 {
-            Err(Error::new(ErrorKind::Other, "Failed to establish connection with web server"))
+            Err(Error::other("Failed to establish connection with web server"))
         } else {
             let dur = time::Duration::from_millis(2000);
             std::thread::sleep(dur);
@@ -28,38 +43,107 @@ fn connect_to_server(ip: &str, retries: u16) -> Result<TcpStream, std::io::Error
         }
 }
 
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum CacheStatus {
+    Hit,
+    Miss,
+    Stale,
+    Bypass,
+    Expired,
+}
+
+impl CacheStatus {
+    fn as_str(self) -> &'static str {
+        match self {
+            CacheStatus::Hit => "HIT",
+            CacheStatus::Miss => "MISS",
+            CacheStatus::Stale => "STALE",
+            CacheStatus::Bypass => "BYPASS",
+            CacheStatus::Expired => "EXPIRED",
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct CacheContext {
+    pub writer: CacheWriter,
+    pub ttl: u64,
+    pub is_available: bool,
+    pub inflight: Arc<InFlight<Arc<HttpMessage>>>,
+    pub store: Arc<dyn CacheStore>,
+    pub purges: Arc<PurgeLog>,
+    pub revalidator: BackgroundPool,
+}
+
 pub fn http_connect(
     st_client: &mut TcpStream,
     push: SyncSender<&'static str>,
     pop: Arc<Mutex<Receiver<&'static str>>>,
-    cache_sender: Sender<FileData>,
-    cache_folder: PathBuf,
-    ttl: u64,
-    is_cache_available: bool,
+    cache: CacheContext,
+    error_pages: Arc<ErrorPages>,
     ) {
     if let Ok(lock) = pop.lock() {
         if let Ok(ip_server) = lock.recv() {
             drop(lock);
             push.send(<&str>::clone(&ip_server)).unwrap();
 
-            if let Ok((mut req_head, mut header, body)) = read_request(st_client) {
+            match read_request(st_client) {
+                Ok((mut req_head, mut header, body)) => {
+
+                    let mut map:HashMap<String, String> = HashMap::new();
 
-                let mut map:HashMap<String, String> = HashMap::new();
+                    let (method, target, _) = parse_cache_info(&req_head);
 
-                let file_path = create_file_path(&cache_folder, req_head.clone());
-                let route = file_path.clone();
+                    if is_purge_request(&method, &target) {
+                        handle_purge(st_client, &method, &target, &header, &cache, &error_pages);
+                        return;
+                    }
 
-                if is_cache_available {
-                    if let Ok(metadata) = Metadata::parse_file(&file_path) {
-                        if !metadata.ttl_check() {
-                            if is_cache_request(&req_head) {
-                                if let Ok(filedata) = FileData::parse_file(file_path, metadata) {
-                                    write_response_from_file(st_client, filedata, &mut map);
-                                } else { handle_file(st_client, ip_server, &mut req_head, &mut header, body, cache_sender, &route, &map, ttl); }
-                            } else { handle_file(st_client, ip_server, &mut req_head, &mut header, body, cache_sender, &route, &map, ttl); }
-                        } else { handle_file(st_client, ip_server, &mut req_head, &mut header, body, cache_sender, &route, &map, ttl); }
-                    } else { handle_file(st_client, ip_server, &mut req_head, &mut header, body, cache_sender, &route, &map, ttl); }
-                } else { handle_file(st_client, ip_server, &mut req_head, &mut header, body, cache_sender, &route, &map, ttl); }
+                    let (cache_key, variant) = match CacheKey::new(&method, &target, &header).map(|key| {
+                        let key = key.to_string();
+                        let fields = cache.store.vary_fields(&key);
+                        let variant = variant_key(&key, &fields, &header);
+                        (key, variant)
+                    }) {
+                        Ok(entry) => entry,
+                        Err(e) => {
+                            write_resp_err_log(&format!("Suspicious request rejected: {}\r\n", e), ip_server);
+                            error_pages.write(st_client, 400);
+                            return;
+                        }
+                    };
+
+                    let bypass = !cache.is_available || !is_cache_request(&method) || forces_refresh(st_client, &header);
+                    let cached = if bypass { CacheLookup::Miss } else { lookup_cache_entry(cache.store.as_ref(), &variant) };
+
+                    match cached {
+                        CacheLookup::Fresh(filedata) => serve_from_cache(st_client, filedata, &method, &header, &mut map, &error_pages, CacheStatus::Hit),
+                        CacheLookup::Stale(metadata) if can_serve_stale(&metadata, "stale-while-revalidate", STALE_WHILE_REVALIDATE_DEFAULT, STALE_WHILE_REVALIDATE_MAX) => {
+                            match read_cache_body(cache.store.as_ref(), &variant, metadata) {
+                                Some(filedata) => {
+                                    let stale = filedata.metadata.clone();
+                                    revalidate_in_background(ip_server, req_head, header.clone(), cache.clone(), cache_key, variant, stale);
+                                    map.insert("warning".to_string(), "110 - \"Response is Stale\"".to_string());
+                                    serve_from_cache(st_client, filedata, &method, &header, &mut map, &error_pages, CacheStatus::Stale);
+                                }
+                                None => handle_file(st_client, ip_server, &mut req_head, &mut header, body, &cache, &cache_key, &variant, &error_pages, None, CacheStatus::Expired),
+                            }
+                        }
+                        CacheLookup::Stale(metadata) => {
+                            handle_file(st_client, ip_server, &mut req_head, &mut header, body, &cache, &cache_key, &variant, &error_pages, Some(metadata), CacheStatus::Expired)
+                        }
+                        CacheLookup::Miss => {
+                            let cache_status = if bypass { CacheStatus::Bypass } else { CacheStatus::Miss };
+                            handle_file(st_client, ip_server, &mut req_head, &mut header, body, &cache, &cache_key, &variant, &error_pages, None, cache_status)
+                        }
+                    }
+                }
+                Err(e) => {
+                    if let Some(status) = e.status() {
+                        write_resp_err_log(&format!("Rejected client request: {}", status), ip_server);
+                        error_pages.write(st_client, status);
+                    }
+                }
             }
         }
     }
@@ -70,27 +154,23 @@ pub fn handle_connection(
     listener: TcpListener,
     push: &SyncSender<&'static str>,
     pop: &Arc<Mutex<Receiver<&'static str>>>,
-    cache_sender: &Sender<FileData>,
-    cache_folder: PathBuf,
-    ttl: u64,
-    is_cache_available: bool,
+    cache: &CacheContext,
+    error_pages: Arc<ErrorPages>,
 ) {
     for stream in listener.incoming() {
         match stream {
             Ok(mut st) => {
                 let pop_clone = Arc::clone(pop);
                 let push_clone = push.clone();
-                let sender = cache_sender.clone();
-                let cache = cache_folder.clone();
+                let cache = cache.clone();
+                let pages = Arc::clone(&error_pages);
                 pool.execute(move || {
                     http_connect(
                         &mut st,
                         push_clone,
                         pop_clone,
-                        sender,
                         cache,
-                        ttl,
-                        is_cache_available,
+                        pages,
                     );
                 });
             }
@@ -99,6 +179,28 @@ pub fn handle_connection(
     }
 }
 
+fn serve_from_cache(
+    st_client: &mut TcpStream,
+    filedata: FileData,
+    method: &str,
+    request_header: &HashMap<String, String>,
+    map: &mut HashMap<String, String>,
+    error_pages: &ErrorPages,
+    cache_status: CacheStatus,
+) {
+    add_cache_headers(map, cache_status, primary_key(&filedata.metadata.key));
+
+    match evaluate_preconditions(method, request_header, &filedata.metadata) {
+        Precondition::Proceed => match select_ranges(method, request_header, &filedata.metadata) {
+            RangeSelection::Full => write_response_from_file(st_client, filedata, map, method == "HEAD"),
+            RangeSelection::Partial(ranges) => write_partial_from_file(st_client, filedata, map, &ranges),
+            RangeSelection::Unsatisfiable => write_range_not_satisfiable(st_client, &filedata.metadata),
+        },
+        Precondition::NotModified => write_not_modified(st_client, &filedata.metadata, map),
+        Precondition::Failed => error_pages.write(st_client, 412),
+    }
+}
+
 #[allow(clippy::too_many_arguments)]
 fn handle_file(
     st_client: &mut TcpStream,
@@ -106,12 +208,122 @@ fn handle_file(
     req_head: &mut String,
     header: &mut HashMap<String, String>,
     body: Vec<u8>,
-    cache_sender: Sender<FileData>,
-    path: &Path,
-    map: &HashMap<String, String>,
-    ttl: u64,
+    cache: &CacheContext,
+    key: &str,
+    variant: &str,
+    error_pages: &ErrorPages,
+    stale: Option<Metadata>,
+    cache_status: CacheStatus,
 ) {
 
+    let (method, _, _) = parse_cache_info(req_head);
+    let mut original_head = req_head.clone();
+    let request_header = header.clone();
+
+    let flight = if cache.is_available && cache_status != CacheStatus::Bypass && is_coalescable(&method, &request_header) {
+        Some(cache.inflight.join(variant))
+    } else {
+        None
+    };
+
+    let leader = match flight {
+        Some(Flight::Follower(slot)) => {
+            if let Some(response) = slot.wait(Duration::from_secs(COALESCE_TIMEOUT)) {
+                write_shared_response(st_client, &response, &method, key);
+                return;
+            }
+            if let CacheLookup::Fresh(filedata) = lookup_cache_entry(cache.store.as_ref(), variant) {
+                serve_from_cache(st_client, filedata, &method, &request_header, &mut HashMap::new(), error_pages, CacheStatus::Hit);
+                return;
+            }
+            None
+        }
+        Some(Flight::Leader(leader)) => Some(leader),
+        None => None,
+    };
+
+    if let Some(stale) = &stale {
+        add_validators(header, stale);
+    }
+
+    let upgrade_head = method == "HEAD" && cache.is_available && cache_status != CacheStatus::Bypass && may_store_request("GET", &request_header);
+
+    // Taken before the fetch, so a purge that lands meanwhile keeps this response out of the cache.
+    let generation = cache.purges.generation();
+
+    match fetch_from_server(ip_server, req_head, header, body, upgrade_head) {
+        Ok((mut resp_head, mut resp_header, mut resp_body)) => {
+            let status = parse_status(&resp_head).unwrap_or(0);
+
+            if let Some(stale) = stale {
+                if status == 304 {
+                    // Followers fall back to the cache once the leader is gone, so it must be refreshed first.
+                    match refresh_stale_entry(cache.store.as_ref(), variant, stale, &resp_header, cache.ttl) {
+                        Some(filedata) => {
+                            drop(leader);
+                            serve_from_cache(st_client, filedata, &method, &request_header, &mut HashMap::new(), error_pages, CacheStatus::Expired)
+                        }
+                        None => {
+                            drop(leader);
+                            handle_file(st_client, ip_server, &mut original_head, &mut request_header.clone(), Vec::new(), cache, key, variant, error_pages, None, CacheStatus::Expired)
+                        }
+                    }
+                    return;
+                }
+
+                if is_server_error(status) && serve_stale_if_error(st_client, cache.store.as_ref(), variant, stale, &method, &request_header, error_pages) {
+                    return;
+                }
+            }
+
+            // A forced refresh replaces the cached copy even while it is still fresh.
+            let overwrite = cache_status == CacheStatus::Bypass;
+            let stored = (method != "HEAD" || upgrade_head) && store_response(&method, status, &request_header, &resp_header, &resp_body, key, cache, generation, overwrite);
+
+            // Followers may select a different variant, so a varying response is not shared.
+            let varies = response_vary_fields(&resp_header).is_none_or(|fields| !fields.is_empty());
+
+            if let Some(leader) = leader {
+                let shared = (resp_head.clone(), resp_header.clone(), resp_body.clone());
+                leader.complete(if stored && !varies { Some(Arc::new(shared)) } else { None });
+            }
+
+            if method == "HEAD" {
+                resp_body.clear();
+            }
+
+            add_cache_headers(&mut resp_header, cache_status, key);
+            write_response(&mut resp_head, &mut resp_header, st_client, resp_body);
+        }
+        Err(status) => {
+            if let Some(stale) = stale {
+                if serve_stale_if_error(st_client, cache.store.as_ref(), variant, stale, &method, &request_header, error_pages) {
+                    return;
+                }
+            }
+
+            error_pages.write(st_client, status);
+        }
+    }
+}
+
+fn fetch_from_server(
+    ip_server: &str,
+    req_head: &mut String,
+    header: &mut HashMap<String, String>,
+    body: Vec<u8>,
+    upgrade_head: bool,
+) -> Result<HttpMessage, u16> {
+    let (method, _, _) = parse_cache_info(req_head);
+
+    // Storable HEAD misses are fetched as GET so the full object can be stored for later GETs.
+    let upstream_method = if method == "HEAD" && upgrade_head {
+        *req_head = req_head.replacen("HEAD", "GET", 1);
+        "GET".to_string()
+    } else {
+        method
+    };
+
     match connect_to_server(ip_server, 3) {
         Ok(server) => {
             write_request(
@@ -121,39 +333,180 @@ fn handle_file(
                 ip_server.to_string(),
                 body,
             );
-            match read_response(&server) {
-                Ok((mut req_head, mut header, body)) => {
+            match read_response(&server, &upstream_method) {
+                Ok(response) => Ok(response),
+                Err(e) => {
+                    let status = match e.kind() {
+                        ErrorKind::WouldBlock | ErrorKind::TimedOut => 504,
+                        _ => 502,
+                    };
+                    write_resp_err_log(&format!("HTTP/1.1 {} {}", status, reason_phrase(status)), ip_server);
+                    Err(status)
+                }
+            }
+        }
+        Err(_) => {
+            let error = "HTTP/1.1 503 Service Unavailable".to_string();
+            write_resp_err_log(&error, ip_server);
+            Err(503)
+        }
+    }
+}
 
-                    let len = body.len();
-                    let temp_body = body.clone();
+#[allow(clippy::too_many_arguments)]
+fn store_response(
+    method: &str,
+    status: u16,
+    request_header: &HashMap<String, String>,
+    header: &HashMap<String, String>,
+    body: &[u8],
+    key: &str,
+    cache: &CacheContext,
+    generation: u64,
+    overwrite: bool,
+) -> bool {
+    let method = if method == "HEAD" { "GET" } else { method };
 
-                    if let Ok(filedata) = 
-                        FileData::default(
-                            ttl, 
-                            len as u64, 
-                            path.to_path_buf(), 
-                            temp_body, 
-                            map.get(&"content-type".to_string()).cloned()
-                        ) {
+    let fields = response_vary_fields(header).unwrap_or_default();
+    let variant = variant_key(key, &fields, request_header);
 
-                        if cache_sender.send(filedata).is_err() {
-                            println!("Failed to queue cache file");
-                        }
-                    }
+    let freshness = if body.len() as u64 <= CACHE_MAX_OBJECT_SIZE && is_storable(method, status, request_header, header) {
+        freshness_lifetime(header, status, cache.ttl)
+    } else {
+        None
+    };
+
+    if let Some(lifetime) = freshness {
+        if let Ok(filedata) = 
+            FileData::default(
+                lifetime.as_secs(), 
+                body.len() as u64, 
+                &variant, 
+                body.to_vec(), 
+                status,
+                header
+            ) {
+
+            cache.writer.submit(filedata, generation, overwrite);
+        }
+    }
+
+    freshness.is_some()
+}
+
+fn is_coalescable(method: &str, request_header: &HashMap<String, String>) -> bool {
+    is_cache_request(method) && !request_header.contains_key("authorization") && !request_header.contains_key("range")
+}
+
+fn write_shared_response(st_client: &mut TcpStream, response: &HttpMessage, method: &str, key: &str) {
+    let (head, headers, body) = response;
+    let body = if method == "HEAD" { Vec::new() } else { body.clone() };
+    let mut headers = headers.clone();
+
+    add_cache_headers(&mut headers, CacheStatus::Hit, key);
+    write_response(&mut head.clone(), &mut headers, st_client, body);
+}
 
-                    write_response(&mut req_head, &mut header, st_client, body);
+fn add_cache_headers(headers: &mut HashMap<String, String>, cache_status: CacheStatus, key: &str) {
+    strip_internal_headers(headers);
+    headers.insert("x-cache".to_string(), cache_status.as_str().to_string());
+    if CACHE_KEY_HEADER && !key.is_empty() {
+        headers.insert("x-cache-key".to_string(), key.to_string());
+    }
+}
+
+// Trusted clients can skip the cached copy with no-cache (refetch and store) or no-store (refetch only).
+fn forces_refresh(st_client: &TcpStream, request_header: &HashMap<String, String>) -> bool {
+    let cache_control = CacheControl::parse(request_header, "cache-control");
+    let pragma = request_header.get("pragma").is_some_and(|pragma| pragma.to_lowercase().contains("no-cache"));
+
+    (cache_control.has("no-cache") || cache_control.has("no-store") || pragma) && is_trusted_client(st_client, &CACHE_BYPASS_ALLOWED_IPS)
+}
+
+// The whole object is revalidated, so a client's range would only bring back part of it.
+fn add_validators(header: &mut HashMap<String, String>, stale: &Metadata) {
+    for name in ["if-none-match", "if-modified-since", "range", "if-range"] {
+        header.remove(name);
+    }
+    if let Some(etag) = stale.get_header("etag") {
+        header.insert("if-none-match".to_string(), etag.clone());
+    }
+    if let Some(last_modified) = stale.get_header("last-modified") {
+        header.insert("if-modified-since".to_string(), last_modified.clone());
+    }
+}
+
+fn is_server_error(status: u16) -> bool {
+    matches!(status, 500 | 502 | 503 | 504)
+}
+
+fn serve_stale_if_error(
+    st_client: &mut TcpStream,
+    store: &dyn CacheStore,
+    variant: &str,
+    stale: Metadata,
+    method: &str,
+    request_header: &HashMap<String, String>,
+    error_pages: &ErrorPages,
+) -> bool {
+    if !can_serve_stale(&stale, "stale-if-error", STALE_IF_ERROR_DEFAULT, STALE_IF_ERROR_MAX) {
+        return false;
+    }
+
+    match read_cache_body(store, variant, stale) {
+        Some(filedata) => {
+            let mut map = HashMap::new();
+            map.insert("warning".to_string(), "111 - \"Revalidation Failed\"".to_string());
+            serve_from_cache(st_client, filedata, method, request_header, &mut map, error_pages, CacheStatus::Stale);
+            true
+        }
+        None => false,
+    }
+}
+
+fn revalidate_in_background(
+    ip_server: &'static str,
+    mut req_head: String,
+    mut header: HashMap<String, String>,
+    cache: CacheContext,
+    key: String,
+    variant: String,
+    stale: Metadata,
+) {
+    // The refresh carries no body, whatever the client's request had.
+    for name in ["content-length", "transfer-encoding"] {
+        header.remove(name);
+    }
+
+    let revalidator = cache.revalidator.clone();
+    let pending = key.clone();
+    let queued = revalidator.try_execute(move || {
+        // Only one refresh per entry; concurrent stale hits keep serving the old copy.
+        let _leader = match cache.inflight.join(&variant) {
+            Flight::Leader(leader) => leader,
+            Flight::Follower(_) => return,
+        };
+
+        let (method, _, _) = parse_cache_info(&req_head);
+        let request_header = header.clone();
+        let upgrade_head = method == "HEAD" && may_store_request("GET", &request_header);
+        add_validators(&mut header, &stale);
+        let generation = cache.purges.generation();
+
+        if let Ok((resp_head, resp_header, resp_body)) = fetch_from_server(ip_server, &mut req_head, &mut header, Vec::new(), upgrade_head) {
+            match parse_status(&resp_head).unwrap_or(0) {
+                304 => {
+                    refresh_stale_entry(cache.store.as_ref(), &variant, stale, &resp_header, cache.ttl);
                 }
-                Err(_) => {
-                    let error = "HTTP/1.1 502 Bad Gateway".to_string();
-                    write_resp_err_log(&error, ip_server);
-                    write_error(error, st_client)
+                status if method != "HEAD" || upgrade_head => {
+                    store_response(&method, status, &request_header, &resp_header, &resp_body, &key, &cache, generation, false);
                 }
+                _ => {}
             }
         }
-        Err(_) => {
-            let error = "HTTP/1.1 503 Service Unavailable".to_string();
-            write_resp_err_log(&error, ip_server);
-            write_failed_to_connect(st_client);
-        }
+    });
+
+    if !queued {
+        println!("Revalidation queue full. Not refreshing {pending}");
     }
 }
@@ -0,0 +1,168 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io::Write;
+use std::net::TcpStream;
+use std::path::Path;
+
+use crate::proxy::config::ERROR_PAGES;
+
+pub struct ErrorPage {
+    content_type: String,
+    body: Vec<u8>,
+}
+
+pub struct ErrorPages {
+    pages: HashMap<u16, ErrorPage>,
+}
+
+impl ErrorPages {
+    pub fn load() -> ErrorPages {
+        let mut pages = HashMap::new();
+
+        for (status, file) in ERROR_PAGES {
+            match fs::read(file) {
+                Ok(body) => {
+                    let content_type = content_type_for(Path::new(file));
+                    pages.insert(status, ErrorPage { content_type, body });
+                }
+                Err(_) => println!("Failed to load error page {} for status {}", file, status),
+            }
+        }
+
+        ErrorPages { pages }
+    }
+
+    pub fn response(&self, status: u16) -> Vec<u8> {
+        let reason = reason_phrase(status);
+        let (content_type, body) = match self.pages.get(&status) {
+            Some(page) => (page.content_type.as_str(), page.body.clone()),
+            None => ("text/html; charset=utf-8", default_body(status, reason)),
+        };
+
+        let head = format!(
+            "HTTP/1.1 {status} {reason}\r\nserver: reverse-proxy-lb\r\ncontent-type: {content_type}\r\ncontent-length: {}\r\nconnection: close\r\n\r\n",
+            body.len()
+        );
+
+        [head.into_bytes(), body].concat()
+    }
+
+    pub fn write(&self, st_client: &mut TcpStream, status: u16) {
+        let response = self.response(status);
+
+        if st_client.write_all(&response).is_err() || st_client.flush().is_err() {
+            println!("Failed to send error response");
+        }
+    }
+}
+
+fn content_type_for(path: &Path) -> String {
+    match path.extension().and_then(|ext| ext.to_str()) {
+        Some("json") => "application/json".to_string(),
+        Some("txt") => "text/plain; charset=utf-8".to_string(),
+        _ => "text/html; charset=utf-8".to_string(),
+    }
+}
+
+fn default_body(status: u16, reason: &str) -> Vec<u8> {
+    format!(
+        "<!DOCTYPE html>\n<html lang=\"en\">\n  <head>\n    <meta charset=\"utf-8\">\n    <title>{status} {reason}</title>\n  </head>\n  <body>\n    <h1>{status} {reason}</h1>\n  </body>\n</html>\n"
+    )
+    .into_bytes()
+}
+
+pub fn reason_phrase(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        203 => "Non-Authoritative Information",
+        204 => "No Content",
+        206 => "Partial Content",
+        301 => "Moved Permanently",
+        302 => "Found",
+        304 => "Not Modified",
+        400 => "Bad Request",
+        403 => "Forbidden",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        408 => "Request Timeout",
+        410 => "Gone",
+        412 => "Precondition Failed",
+        413 => "Content Too Large",
+        416 => "Range Not Satisfiable",
+        429 => "Too Many Requests",
+        431 => "Request Header Fields Too Large",
+        500 => "Internal Server Error",
+        501 => "Not Implemented",
+        502 => "Bad Gateway",
+        503 => "Service Unavailable",
+        504 => "Gateway Timeout",
+        _ => "Unknown",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pages() -> ErrorPages {
+        let page = ErrorPage {
+            content_type: "application/json".to_string(),
+            body: b"{\"error\":\"down\"}".to_vec(),
+        };
+
+        ErrorPages {
+            pages: HashMap::from([(502, page)]),
+        }
+    }
+
+    fn split(response: &[u8]) -> (String, Vec<u8>) {
+        let text = String::from_utf8_lossy(response).to_string();
+        let (head, body) = text.split_once("\r\n\r\n").unwrap();
+
+        (head.to_string(), body.as_bytes().to_vec())
+    }
+
+    #[test]
+    fn configured_page_is_framed() {
+        let (head, body) = split(&pages().response(502));
+        let lines: Vec<&str> = head.split("\r\n").collect();
+
+        assert_eq!(lines[0], "HTTP/1.1 502 Bad Gateway");
+        assert!(lines.contains(&"content-type: application/json"));
+        assert!(lines.contains(&"content-length: 16"));
+        assert!(lines.contains(&"connection: close"));
+        assert_eq!(body, b"{\"error\":\"down\"}");
+    }
+
+    #[test]
+    fn missing_page_falls_back_to_generated_html() {
+        let (head, body) = split(&pages().response(404));
+        let body = String::from_utf8(body).unwrap();
+
+        assert!(head.starts_with("HTTP/1.1 404 Not Found\r\n"));
+        assert!(head.contains("content-type: text/html; charset=utf-8\r\n"));
+        assert!(head.contains(&format!("content-length: {}\r\n", body.len())));
+        assert!(head.ends_with("connection: close"));
+        assert!(body.contains("<h1>404 Not Found</h1>"));
+    }
+
+    #[test]
+    fn unknown_status_keeps_its_code() {
+        let (head, _) = split(&pages().response(599));
+
+        assert!(head.starts_with("HTTP/1.1 599 Unknown\r\n"));
+    }
+
+    #[test]
+    fn content_type_follows_the_extension() {
+        assert_eq!(content_type_for(Path::new("down.json")), "application/json");
+        assert_eq!(
+            content_type_for(Path::new("down.txt")),
+            "text/plain; charset=utf-8"
+        );
+        assert_eq!(
+            content_type_for(Path::new("failed.html")),
+            "text/html; charset=utf-8"
+        );
+    }
+}
@@ -1,5 +1,7 @@
+pub mod admin;
 pub mod config;
 pub mod connecting;
+pub mod error_response;
 pub mod request;
 pub mod responser;
 pub mod threadpool;
\ No newline at end of file
@@ -1,30 +1,62 @@
 use std::collections::HashMap;
 use std::fs;
-use std::io::{BufRead, BufReader, BufWriter, Read, Write};
+use std::io::{BufRead, BufReader, BufWriter, Error, ErrorKind, Read, Write};
 use std::net::TcpStream;
 
-use crate::proxy::config::DIR_LOG;
+use crate::proxy::config::{DIR_LOG, MAX_BODY_SIZE};
 
-pub fn read_request(
-    mut st_client: &TcpStream,
-) -> Result<(String, HashMap<String, String>, Vec<u8>), std::io::Error> {
+pub type HttpMessage = (String, HashMap<String, String>, Vec<u8>);
+
+#[derive(Debug)]
+pub enum RequestError {
+    Io(std::io::Error),
+    BadRequest,
+    PayloadTooLarge,
+}
+
+impl RequestError {
+    pub fn status(&self) -> Option<u16> {
+        match self {
+            RequestError::Io(_) => None,
+            RequestError::BadRequest => Some(400),
+            RequestError::PayloadTooLarge => Some(413),
+        }
+    }
+}
+
+impl From<std::io::Error> for RequestError {
+    fn from(error: std::io::Error) -> Self {
+        RequestError::Io(error)
+    }
+}
+
+pub fn read_request(mut st_client: &TcpStream) -> Result<HttpMessage, RequestError> {
     let mut buf_reader = BufReader::new(&mut st_client);
     let mut req: String = String::new();
     let mut req_head: String = String::new();
-    buf_reader.read_line(&mut req_head)?;
-    //buf_reader.read_line(&mut req_head)?;
-    //buf_reader.read_line(&mut req_head)?;
+    if buf_reader.read_line(&mut req_head)? == 0 {
+        return Err(RequestError::Io(Error::from(ErrorKind::UnexpectedEof)));
+    }
+
+    if req_head.trim_end().split(' ').count() != 3 || !req_head.ends_with("\r\n") {
+        return Err(RequestError::BadRequest);
+    }
 
     loop {
-        buf_reader.read_line(&mut req)?;
-        if req.ends_with("\r\n\r\n") {
+        if buf_reader.read_line(&mut req)? == 0 {
+            return Err(RequestError::BadRequest);
+        }
+        if req == "\r\n" || req.ends_with("\r\n\r\n") {
             break;
         }
     }
 
     write_req_log(&req_head, &req, "Request Client".to_string(), &String::new());
     let headers = parse_request(&req);
-    let content_length = get_content_length(&headers);
+    let content_length = get_content_length(&headers)?;
+    if content_length > MAX_BODY_SIZE {
+        return Err(RequestError::PayloadTooLarge);
+    }
     let mut body = vec![0; content_length];
 
     buf_reader.read_exact(&mut body)?;
@@ -32,23 +64,20 @@ pub fn read_request(
     Ok((req_head, headers, body))
 }
 
-fn get_content_length(req: &HashMap<String, String>) -> usize {
+fn get_content_length(req: &HashMap<String, String>) -> Result<usize, RequestError> {
     match req.get("content-length") {
-        Some(s) => s.parse().unwrap_or(0),
-        None => 0,
+        Some(s) => s.parse().map_err(|_| RequestError::BadRequest),
+        None => Ok(0),
     }
 }
 
 fn parse_request(request: &str) -> HashMap<String, String> {
     let mut headers: HashMap<String, String> = HashMap::new();
-    let lines: Vec<String> = request.split("\r\n").map(|s| s.to_owned()).collect();
 
-    for ln in 0..lines.len() - 1 {
-        let (k, v) = lines[ln]
-        .split_once(':')
-        .unwrap_or(("response_code", &lines[0]));
-
-        headers.insert(k.to_lowercase(), v.trim().to_string());
+    for line in request.split("\r\n") {
+        if let Some((k, v)) = line.split_once(':') {
+            headers.insert(k.trim().to_lowercase(), v.trim().to_string());
+        }
     }
 
     headers
@@ -141,6 +170,6 @@ pub fn parse_cache_info(request: &str) -> (String, String, String) {
     (status_line[0].to_owned(), status_line[1].to_owned(), status_line[2].to_owned())
 }
 
-pub fn is_cache_request(method: &String) -> bool {
-    method.eq("GET")
+pub fn is_cache_request(method: &str) -> bool {
+    method == "GET" || method == "HEAD"
 }
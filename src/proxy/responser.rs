@@ -1,55 +1,148 @@
 use std::collections::HashMap;
 use std::fs;
-use std::io::{BufRead, BufReader, BufWriter, Read, Write};
+use std::io::{self, BufRead, BufReader, BufWriter, Error, ErrorKind, Read, Seek, SeekFrom, Write};
 use std::net::TcpStream;
-use crate::cache::filedata::FileData;
+use crate::cache::filedata::{CacheBody, FileData};
+use crate::cache::metadata::Metadata;
+use uuid::Uuid;
 
-use crate::proxy::config::DIR_LOG;
+use crate::proxy::config::{DIR_LOG, INTERNAL_RESPONSE_HEADERS, MAX_RESPONSE_SIZE};
+use crate::proxy::error_response::reason_phrase;
+use crate::proxy::request::HttpMessage;
 
-pub fn read_response(
-    mut stream: &TcpStream,
-) -> Result<(String, HashMap<String, String>, Vec<u8>), std::io::Error> {
-    let mut buf_reader = BufReader::new(&mut stream);
+pub fn read_response<R: Read>(stream: R, method: &str) -> Result<HttpMessage, std::io::Error> {
+    let mut buf_reader = BufReader::new(stream);
     let mut req: String = String::new();
     let mut req_head: String = String::new();
     buf_reader.read_line(&mut req_head)?;
-    buf_reader.read_line(&mut req_head)?;
-    buf_reader.read_line(&mut req_head)?;
+
+    let status = match parse_status(&req_head) {
+        Some(status) => status,
+        None => return Err(Error::new(ErrorKind::InvalidData, "Invalid status line from web server")),
+    };
 
     loop {
-        buf_reader.read_line(&mut req)?;
-        if req.ends_with("\r\n\r\n") {
+        if buf_reader.read_line(&mut req)? == 0 {
+            return Err(Error::from(ErrorKind::UnexpectedEof));
+        }
+        if req == "\r\n" || req.ends_with("\r\n\r\n") {
             break;
         }
     }
 
     write_resp_log(&req_head, &req, "Response Web Server".to_string());
-    let headers = parse_response(&req);
-    let content_length = get_content_length(&headers);
-    let mut body = vec![0; content_length];
+    let mut headers = parse_response(&req);
+
+    if !has_body(method, status) {
+        return Ok((req_head, headers, Vec::new()));
+    }
 
-    buf_reader.read_exact(&mut body)?;
+    let body = if is_chunked(&headers) {
+        let body = read_chunked_body(&mut buf_reader, MAX_RESPONSE_SIZE)?;
+        headers.remove("transfer-encoding");
+        headers.insert("content-length".to_string(), body.len().to_string());
+        body
+    } else if let Some(content_length) = get_content_length(&headers) {
+        if content_length > MAX_RESPONSE_SIZE {
+            return Err(response_too_large());
+        }
+        let mut body = vec![0; content_length];
+        buf_reader.read_exact(&mut body)?;
+        body
+    } else {
+        let body = read_to_close(&mut buf_reader, MAX_RESPONSE_SIZE)?;
+        headers.insert("content-length".to_string(), body.len().to_string());
+        body
+    };
 
     Ok((req_head, headers, body))
 }
 
-fn get_content_length(req: &HashMap<String, String>) -> usize {
+pub fn parse_status(status_line: &str) -> Option<u16> {
+    let mut parts = status_line.split(' ');
+
+    match parts.next() {
+        Some(version) if version.starts_with("HTTP/") => parts.next()?.trim().parse().ok(),
+        _ => None,
+    }
+}
+
+pub fn has_body(method: &str, status: u16) -> bool {
+    !(method == "HEAD" || (100..200).contains(&status) || status == 204 || status == 304)
+}
+
+fn is_chunked(headers: &HashMap<String, String>) -> bool {
+    match headers.get("transfer-encoding") {
+        Some(te) => te.to_lowercase().trim_end().ends_with("chunked"),
+        None => false,
+    }
+}
+
+fn read_chunked_body<R: BufRead>(reader: &mut R, limit: usize) -> Result<Vec<u8>, std::io::Error> {
+    let mut body = Vec::new();
+
+    loop {
+        let mut size_line = String::new();
+        if reader.read_line(&mut size_line)? == 0 {
+            return Err(Error::from(ErrorKind::UnexpectedEof));
+        }
+
+        let size = size_line.split(';').next().unwrap_or("").trim();
+        let size = match usize::from_str_radix(size, 16) {
+            Ok(size) => size,
+            Err(_) => return Err(Error::new(ErrorKind::InvalidData, "Invalid chunk size from web server")),
+        };
+
+        if size == 0 {
+            loop {
+                let mut trailer = String::new();
+                if reader.read_line(&mut trailer)? == 0 || trailer == "\r\n" {
+                    return Ok(body);
+                }
+            }
+        }
+
+        let start = body.len();
+        match start.checked_add(size) {
+            Some(end) if end <= limit => body.resize(end, 0),
+            _ => return Err(response_too_large()),
+        }
+        reader.read_exact(&mut body[start..])?;
+
+        let mut crlf = [0u8; 2];
+        reader.read_exact(&mut crlf)?;
+    }
+}
+
+fn read_to_close<R: Read>(reader: &mut R, limit: usize) -> Result<Vec<u8>, std::io::Error> {
+    let mut body = Vec::new();
+    reader.take(limit as u64 + 1).read_to_end(&mut body)?;
+
+    if body.len() > limit {
+        return Err(response_too_large());
+    }
+
+    Ok(body)
+}
+
+fn response_too_large() -> Error {
+    Error::new(ErrorKind::InvalidData, "Response from web server is too large")
+}
+
+fn get_content_length(req: &HashMap<String, String>) -> Option<usize> {
     match req.get("content-length") {
-        Some(s) => s.parse().unwrap_or(0),
-        None => 0,
+        Some(s) => s.parse().ok(),
+        None => None,
     }
 }
 
 fn parse_response(request: &str) -> HashMap<String, String> {
     let mut headers: HashMap<String, String> = HashMap::new();
-    let lines: Vec<String> = request.split("\r\n").map(|s| s.to_owned()).collect();
-
-    for ln in 0..lines.len() - 1 {
-        let (k, v) = lines[ln]
-            .split_once(':')
-            .unwrap_or(("response_code", &lines[0]));
 
-        headers.insert(k.to_lowercase(), v.trim().to_string());
+    for line in request.split("\r\n") {
+        if let Some((k, v)) = line.split_once(':') {
+            headers.insert(k.trim().to_lowercase(), v.trim().to_string());
+        }
     }
 
     headers
@@ -83,73 +176,156 @@ pub fn write_response(
     }
 }
 
-pub fn write_response_from_file(stream: &TcpStream, filedata: FileData, map: &mut HashMap<String, String>) {
+pub fn write_response_from_file(stream: &TcpStream, filedata: FileData, map: &mut HashMap<String, String>, head_only: bool) {
 
-    let version = "HTTP/1.1".to_string();
-    let code = "200";
-    let response = "OK".to_string();
+    let length = filedata.metadata.content_length;
 
-    map.insert("server".to_string(), "reverse-proxy-lb".to_string());
+    map.insert("content-length".to_string(), length.to_string());
+    if filedata.metadata.get_status() == 200 { map.insert("accept-ranges".to_string(), "bytes".to_string()); }
 
-    if let Some(content_type) = filedata.metadata.content_type {
+    let head = cached_head(&filedata.metadata, filedata.metadata.get_status(), map);
+    let mut body = filedata.body;
+    let mut writer = stream;
 
-        map.insert("content-type".to_string(), content_type);
-    }
+    let result = writer
+        .write_all(&head)
+        .and_then(|_| if head_only { Ok(()) } else { copy_body(stream, &mut body, 0, length) })
+        .and_then(|_| writer.flush());
 
-    map.insert("content-length".to_string(), filedata.metadata.content_length.to_string());
+    if result.is_err() { println!("Failed to write response"); }
+}
 
-    let mut buf_writer = BufWriter::new(stream);
+pub fn write_partial_from_file(stream: &TcpStream, filedata: FileData, map: &mut HashMap<String, String>, ranges: &[(u64, u64)]) {
 
-    let mut status = {
+    let length = filedata.metadata.content_length;
+    let mut body = filedata.body;
+    let mut writer = stream;
 
-        let version = version.as_bytes();
-        let code = code.as_bytes();
-        let response = response.as_bytes();
-        let sp = [b' '];
-        let crlf = [0x0D, 0x0A];
-        let line = [version, &sp, code, &sp, response, &crlf].concat();
+    let result = match ranges {
 
-        line.to_vec()
-    };
+        [(first, last)] => {
 
-    let content = {
+            map.insert("content-range".to_string(), format!("bytes {first}-{last}/{length}"));
+            map.insert("content-length".to_string(), (last - first + 1).to_string());
 
-        let mut temp = {
-            for (key, value) in map.iter() {
+            let head = cached_head(&filedata.metadata, 206, map);
 
-                let mut element = format!("{key}:{value}\r\n").as_bytes().to_vec();
-                status.append(&mut element);
-            }
+            writer.write_all(&head).and_then(|_| copy_body(stream, &mut body, *first, last - first + 1))
+        },
+        _ => {
 
-            status.push(0x0D);
-            status.push(0x0A);
+            let boundary = Uuid::new_v4().simple().to_string();
+            let content_type = filedata.metadata.get_content_type().cloned().unwrap_or_else(|| "application/octet-stream".to_string());
+            let part_heads: Vec<String> = ranges
+                .iter()
+                .map(|(first, last)| format!("--{boundary}\r\ncontent-type: {content_type}\r\ncontent-range: bytes {first}-{last}/{length}\r\n\r\n"))
+                .collect();
+            let closing = format!("--{boundary}--\r\n");
 
-            status
-        };
+            let total: u64 = ranges.iter().zip(&part_heads).map(|((first, last), head)| head.len() as u64 + last - first + 1 + 2).sum::<u64>() + closing.len() as u64;
+
+            map.insert("content-type".to_string(), format!("multipart/byteranges; boundary={boundary}"));
+            map.insert("content-length".to_string(), total.to_string());
 
-        let mut body = filedata.content_data.clone();
-        temp.append(&mut body);
+            let head = cached_head(&filedata.metadata, 206, map);
 
-        temp
+            let mut result = writer.write_all(&head);
+
+            for ((first, last), part_head) in ranges.iter().zip(&part_heads) {
+
+                result = result
+                    .and_then(|_| writer.write_all(part_head.as_bytes()))
+                    .and_then(|_| copy_body(stream, &mut body, *first, last - first + 1))
+                    .and_then(|_| writer.write_all(b"\r\n"));
+            }
+
+            result.and_then(|_| writer.write_all(closing.as_bytes()))
+        },
     };
 
-    let size = content.len();
-    let bf_size = if size < 2048 { size } else { size / 1024 };
+    if result.and_then(|_| writer.flush()).is_err() { println!("Failed to write response"); }
+}
 
-    for chunk in content.chunks(bf_size) {
+fn copy_body(mut stream: &TcpStream, body: &mut CacheBody, start: u64, length: u64) -> Result<(), Error> {
 
-        let mut total_bytes_written = 0;
+    match body {
 
-        while total_bytes_written < chunk.len() {
+        CacheBody::Memory(bytes) => { stream.write_all(&bytes[start as usize..(start + length) as usize]) },
+        CacheBody::File { file, offset } => {
 
-            if let Ok(bytes_written) = buf_writer.write(&chunk[total_bytes_written..]) {
+            // io::copy from a file to a socket uses sendfile/splice where the platform has them.
+            file.seek(SeekFrom::Start(*offset + start))?;
+            let copied = io::copy(&mut Read::by_ref(file).take(length), &mut stream)?;
 
-                total_bytes_written += bytes_written;
+            if copied == length { Ok(()) } else { Err(Error::new(ErrorKind::UnexpectedEof, "Cache file truncated")) }
+        },
+    }
+}
+
+pub fn write_range_not_satisfiable(mut stream: &TcpStream, metadata: &Metadata) {
+
+    let head = format!(
+        "HTTP/1.1 416 {}\r\nserver:reverse-proxy-lb\r\ncontent-range:bytes */{}\r\ncontent-length:0\r\n\r\n",
+        reason_phrase(416),
+        metadata.content_length
+    );
+
+    if stream.write_all(head.as_bytes()).is_err() || stream.flush().is_err() {
+        println!("Failed to write response");
+    }
+}
+
+fn cached_head(metadata: &Metadata, status: u16, map: &mut HashMap<String, String>) -> Vec<u8> {
+
+    for (key, value) in metadata.get_headers() {
+
+        map.entry(key.clone()).or_insert_with(|| value.clone());
+    }
+
+    strip_internal_headers(map);
+    map.entry("server".to_string()).or_insert_with(|| "reverse-proxy-lb".to_string());
+    map.insert("age".to_string(), metadata.get_age().as_secs().to_string());
+
+    let mut head = format!("HTTP/1.1 {} {}\r\n", status, reason_phrase(status));
 
-                if buf_writer.flush().is_err() { println!("Failed to flush BufWriter"); return; }
-            } else { println!("Failed to write response"); return; }
+    for (key, value) in map.iter() {
+
+        head.push_str(&format!("{key}:{value}\r\n"));
+    }
+
+    head.push_str("\r\n");
+    head.into_bytes()
+}
+
+// Headers the origin sends for the cache itself, such as purge tags, are not shown to clients.
+pub fn strip_internal_headers(headers: &mut HashMap<String, String>) {
+
+    for name in INTERNAL_RESPONSE_HEADERS {
+        headers.remove(name);
+    }
+}
+
+pub fn write_not_modified(mut stream: &TcpStream, metadata: &Metadata, map: &HashMap<String, String>) {
+
+    let mut head = "HTTP/1.1 304 Not Modified\r\n".to_string();
+
+    for name in ["cache-control", "content-location", "date", "etag", "expires", "last-modified", "vary"] {
+
+        if let Some(value) = metadata.get_header(name) {
+            head.push_str(&format!("{name}:{value}\r\n"));
         }
     }
+
+    for (key, value) in map {
+
+        head.push_str(&format!("{key}:{value}\r\n"));
+    }
+
+    head.push_str(&format!("age:{}\r\n\r\n", metadata.get_age().as_secs()));
+
+    if stream.write_all(head.as_bytes()).is_err() || stream.flush().is_err() {
+        println!("Failed to write response");
+    }
 }
 
 fn hashmap_to_vec(bytes: &mut Vec<u8>, headers: &mut HashMap<String, String>) {
@@ -173,12 +349,6 @@ fn concat_resp(
     req_bytes
 }
 
-pub fn write_error(status_line: String, st_client: &mut TcpStream) {
-    if st_client.write(status_line.as_bytes()).is_err() {
-        println!("Failed to send error response");
-    }
-}
-
 fn write_resp_log(req: &String, req_head: &String, type_req: String) {
     let req_total = format!("\r\n{}\r\n{}{}", type_req, req, req_head);
     if let Ok(mut old_text) = fs::read_to_string(DIR_LOG) {
@@ -204,15 +374,96 @@ pub fn write_resp_err_log(error: &String, ip: &str) {
 
 }
 
-pub fn write_failed_to_connect(st_client: &mut TcpStream) {
-    let status_line = "HTTP/1.1 200 OK";
-    let contents = fs::read_to_string("failed.html").unwrap();
-    let length = contents.len();
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn read(method: &str, response: &str) -> Result<HttpMessage, Error> {
+        read_response(response.as_bytes(), method)
+    }
+
+    #[test]
+    fn reads_content_length_body() {
+        let (head, headers, body) = read(
+            "GET",
+            "HTTP/1.1 200 OK\r\ncontent-length: 5\r\n\r\nhello trailing",
+        )
+        .unwrap();
+
+        assert_eq!(head, "HTTP/1.1 200 OK\r\n");
+        assert_eq!(headers["content-length"], "5");
+        assert_eq!(body, b"hello");
+    }
+
+    #[test]
+    fn skips_body_for_head_204_and_304() {
+        for (method, status) in [("HEAD", 200), ("GET", 204), ("GET", 304)] {
+            let response = format!("HTTP/1.1 {status} X\r\ncontent-length: 5\r\n\r\nhello");
+            let (_, headers, body) = read(method, &response).unwrap();
+
+            assert_eq!(headers["content-length"], "5");
+            assert!(body.is_empty());
+        }
+
+        assert!(has_body("GET", 200));
+        assert!(!has_body("GET", 101));
+    }
+
+    #[test]
+    fn decodes_chunked_body() {
+        let (_, headers, body) = read(
+            "GET",
+            "HTTP/1.1 200 OK\r\ntransfer-encoding: chunked\r\n\r\n5;ext=1\r\nhello\r\n1\r\n!\r\n0\r\nx-trailer: 1\r\n\r\n",
+        )
+        .unwrap();
+
+        assert!(!headers.contains_key("transfer-encoding"));
+        assert_eq!(headers["content-length"], "6");
+        assert_eq!(body, b"hello!");
+    }
+
+    #[test]
+    fn reads_close_delimited_body() {
+        let (_, headers, body) = read("GET", "HTTP/1.1 200 OK\r\nserver: x\r\n\r\nuntil close").unwrap();
+
+        assert_eq!(headers["content-length"], "11");
+        assert_eq!(body, b"until close");
+    }
+
+    #[test]
+    fn rejects_malformed_responses() {
+        assert!(read("GET", "garbage\r\n\r\n").is_err());
+        assert_eq!(
+            read("GET", "HTTP/1.1 200 OK\r\nserver: x\r\n").unwrap_err().kind(),
+            ErrorKind::UnexpectedEof
+        );
+        assert_eq!(
+            read("GET", "HTTP/1.1 200 OK\r\ntransfer-encoding: chunked\r\n\r\nzz\r\n")
+                .unwrap_err()
+                .kind(),
+            ErrorKind::InvalidData
+        );
+    }
+
+    #[test]
+    fn rejects_bodies_above_the_limit() {
+        let mut chunked = &b"4\r\nabcd\r\n4\r\nefgh\r\n0\r\n\r\n"[..];
+        assert_eq!(read_chunked_body(&mut chunked, 8).unwrap(), b"abcdefgh");
+
+        let mut chunked = &b"4\r\nabcd\r\n5\r\nefghi\r\n0\r\n\r\n"[..];
+        let err = read_chunked_body(&mut chunked, 8).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+
+        // A size that would overflow is refused before anything is allocated.
+        let mut chunked = &b"1\r\na\r\nffffffffffffffff\r\n"[..];
+        let err = read_chunked_body(&mut chunked, usize::MAX).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
 
-    let response =
-        format!("{status_line}\r\nContent-Length: {length}\r\n\r\n{contents}");
+        assert_eq!(read_to_close(&mut &b"12345678"[..], 8).unwrap(), b"12345678");
+        let err = read_to_close(&mut &b"123456789"[..], 8).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
 
-    if st_client.write(response.as_bytes()).is_err() {
-        println!("Failed to send error response");
+        let response = format!("HTTP/1.1 200 OK\r\ncontent-length: {}\r\n\r\n", MAX_RESPONSE_SIZE + 1);
+        assert_eq!(read("GET", &response).unwrap_err().kind(), ErrorKind::InvalidData);
     }
 }
@@ -1,4 +1,4 @@
-use std::sync::mpsc::{Receiver, SyncSender};
+use std::sync::mpsc::{Receiver, SyncSender, TrySendError};
 use std::{
     sync::{mpsc, Arc, Mutex},
     thread,
@@ -18,6 +18,12 @@ pub struct ThreadPool {
 
 type Job = Box<dyn FnOnce() + Send + 'static>;
 
+// Fixed workers behind a bounded queue, for work that may be skipped when it piles up.
+#[derive(Clone)]
+pub struct BackgroundPool {
+    sender: SyncSender<Job>,
+}
+
 impl ThreadPool {
     pub fn new(size: usize) -> ThreadPool {
         let (sender, receiver) = mpsc::channel();
@@ -43,6 +49,40 @@ impl ThreadPool {
     }
 }
 
+impl BackgroundPool {
+    pub fn new(size: usize, queue_size: usize) -> BackgroundPool {
+        let (sender, receiver) = mpsc::sync_channel::<Job>(queue_size);
+        let receiver = Arc::new(Mutex::new(receiver));
+
+        for _ in 0..size.max(1) {
+            let receiver = Arc::clone(&receiver);
+            thread::spawn(move || loop {
+                let job = match receiver.lock() {
+                    Ok(lock) => lock.recv(),
+                    Err(_) => return,
+                };
+                match job {
+                    Ok(job) => job(),
+                    Err(_) => return,
+                }
+            });
+        }
+
+        BackgroundPool { sender }
+    }
+
+    // Returns false without running the job if the queue is full.
+    pub fn try_execute<F>(&self, f: F) -> bool
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        match self.sender.try_send(Box::new(f)) {
+            Ok(_) => true,
+            Err(TrySendError::Full(_)) | Err(TrySendError::Disconnected(_)) => false,
+        }
+    }
+}
+
 impl Worker {
     fn new(id: usize, receiver: Arc<Mutex<mpsc::Receiver<Job>>>) -> Worker {
         let thread = thread::spawn(move || loop {